@@ -0,0 +1,230 @@
+#[cfg(test)]
+mod test;
+
+use crate::address_index::address_extractor::AddressExtractor;
+use crate::address_index::script::ScriptKind;
+use bech32::primitives::decode::CheckedHrpstring;
+use bech32::{Bech32, Hrp};
+use serde::Serialize;
+
+const SAPLING_PAYMENT_ADDRESS_LENGTH: usize = 43;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Network {
+    Mainnet,
+    Testnet,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressType {
+    PubKeyHash,
+    ScriptHash,
+    Staking,
+    Exchange,
+    Shield,
+}
+
+const NETWORKS: [Network; 2] = [Network::Mainnet, Network::Testnet];
+const TRANSPARENT_TYPES: [AddressType; 4] = [
+    AddressType::PubKeyHash,
+    AddressType::ScriptHash,
+    AddressType::Staking,
+    AddressType::Exchange,
+];
+
+impl Network {
+    /**
+     * Returns the base58 version prefix, or None for shield addresses
+     */
+    pub fn base58_prefix(&self, address_type: AddressType) -> Option<&'static [u8]> {
+        use AddressType::*;
+        match (self, address_type) {
+            (Self::Mainnet, PubKeyHash) => Some(&[30]),
+            (Self::Mainnet, ScriptHash) => Some(&[13]),
+            (Self::Mainnet, Staking) => Some(&[63]),
+            (Self::Mainnet, Exchange) => Some(&[0x01, 0xb9, 0xa2]),
+            (Self::Testnet, PubKeyHash) => Some(&[139]),
+            (Self::Testnet, ScriptHash) => Some(&[19]),
+            (Self::Testnet, Staking) => Some(&[73]),
+            (Self::Testnet, Exchange) => Some(&[0x01, 0xb9, 0xb1]),
+            (_, Shield) => None,
+        }
+    }
+
+    pub fn sapling_hrp(&self) -> &'static str {
+        match self {
+            Self::Mainnet => "ps",
+            Self::Testnet => "ptestsapling",
+        }
+    }
+}
+
+/// A decoded PIVX address.
+/// `payload` is the key or script hash for transparent addresses,
+/// and the diversifier followed by pk_d for shield addresses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    pub address_type: AddressType,
+    pub network: Network,
+    pub payload: Vec<u8>,
+}
+
+/// Result of `explorer::explorer_validate_address`
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AddressInfo {
+    pub is_valid: bool,
+    pub address_type: Option<AddressType>,
+    pub network: Option<Network>,
+    pub script_pub_key: Option<String>,
+}
+
+impl Address {
+    pub fn new(address_type: AddressType, network: Network, payload: &[u8]) -> Self {
+        Self {
+            address_type,
+            network,
+            payload: payload.to_vec(),
+        }
+    }
+
+    fn checksum(data: &[u8]) -> [u8; 4] {
+        let hash = AddressExtractor::double_sha256(data);
+        [hash[0], hash[1], hash[2], hash[3]]
+    }
+
+    fn decode_transparent(address: &str) -> Option<Self> {
+        let mut bytes = bs58::decode(address).into_vec().ok()?;
+        if bytes.len() < 4 {
+            return None;
+        }
+        let checksum = bytes.split_off(bytes.len() - 4);
+        if Self::checksum(&bytes) != checksum.as_slice() {
+            return None;
+        }
+        for network in NETWORKS {
+            for address_type in TRANSPARENT_TYPES {
+                let prefix = network.base58_prefix(address_type);
+                if let Some(hash) = prefix.and_then(|prefix| bytes.strip_prefix(prefix)) {
+                    if hash.len() == 20 {
+                        return Some(Self::new(address_type, network, hash));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn decode_shield(address: &str) -> Option<Self> {
+        let checked = CheckedHrpstring::new::<Bech32>(address).ok()?;
+        let network = NETWORKS
+            .into_iter()
+            .find(|network| checked.hrp().as_str() == network.sapling_hrp())?;
+        let payload: Vec<u8> = checked.byte_iter().collect();
+        if payload.len() != SAPLING_PAYMENT_ADDRESS_LENGTH {
+            return None;
+        }
+        Some(Self::new(AddressType::Shield, network, &payload))
+    }
+
+    /**
+     * Decodes a transparent or shield address, checking its checksum and prefix
+     */
+    pub fn decode(address: &str) -> Option<Self> {
+        Self::decode_transparent(address).or_else(|| Self::decode_shield(address))
+    }
+
+    pub fn encode(&self) -> String {
+        match self.network.base58_prefix(self.address_type) {
+            Some(prefix) => {
+                let mut bytes = prefix.to_vec();
+                bytes.extend_from_slice(&self.payload);
+                let checksum = Self::checksum(&bytes);
+                bytes.extend_from_slice(&checksum);
+                bs58::encode(bytes).into_string()
+            }
+            None => {
+                let hrp = Hrp::parse_unchecked(self.network.sapling_hrp());
+                // Only fails if the address is longer than 90 characters,
+                // which a 43 bytes payload never is
+                bech32::encode::<Bech32>(hrp, &self.payload).expect("Payload is too long")
+            }
+        }
+    }
+
+    /**
+     * Returns the scriptPubKey paying to this address.
+     * Staking and shield addresses can't be paid to directly, so they return None
+     */
+    pub fn to_script_pub_key(&self) -> Option<Vec<u8>> {
+        let p2pkh = |hash: &[u8]| {
+            let mut script = vec![0x76, 0xa9, 0x14];
+            script.extend_from_slice(hash);
+            script.extend_from_slice(&[0x88, 0xac]);
+            script
+        };
+        match self.address_type {
+            AddressType::PubKeyHash => Some(p2pkh(&self.payload)),
+            AddressType::Exchange => {
+                let mut script = vec![0xe0];
+                script.extend(p2pkh(&self.payload));
+                Some(script)
+            }
+            AddressType::ScriptHash => {
+                let mut script = vec![0xa9, 0x14];
+                script.extend_from_slice(&self.payload);
+                script.push(0x87);
+                Some(script)
+            }
+            AddressType::Staking | AddressType::Shield => None,
+        }
+    }
+
+    /**
+     * Returns the address a scriptPubKey pays to, if it has exactly one.
+     * Cold stake scripts return the owner
+     */
+    pub fn from_script_pub_key(script: &[u8], network: Network) -> Option<Self> {
+        let (address_type, hash) = match ScriptKind::classify(script) {
+            ScriptKind::PubKeyHash(hash) => (AddressType::PubKeyHash, hash),
+            ScriptKind::ScriptHash(hash) => (AddressType::ScriptHash, hash),
+            ScriptKind::ExchangeAddress(hash) => (AddressType::Exchange, hash),
+            ScriptKind::ColdStake { owner, .. } => (AddressType::PubKeyHash, owner),
+            _ => return None,
+        };
+        Some(Self::new(address_type, network, &hash))
+    }
+
+    /**
+     * Exchange addresses are a different encoding of the same key hash as a regular address.
+     * Returns the other form of this address, if it's one of the two
+     */
+    pub fn get_exchange_pair(&self) -> Option<Self> {
+        let address_type = match self.address_type {
+            AddressType::PubKeyHash => AddressType::Exchange,
+            AddressType::Exchange => AddressType::PubKeyHash,
+            _ => return None,
+        };
+        Some(Self::new(address_type, self.network, &self.payload))
+    }
+}
+
+impl AddressInfo {
+    pub fn new(address: &str) -> Self {
+        match Address::decode(address) {
+            Some(address) => Self {
+                is_valid: true,
+                address_type: Some(address.address_type),
+                network: Some(address.network),
+                script_pub_key: address.to_script_pub_key().map(hex::encode),
+            },
+            None => Self {
+                is_valid: false,
+                address_type: None,
+                network: None,
+                script_pub_key: None,
+            },
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use super::*;
+
+const HASH: &str = "5b589b431bc3b563de290426ce74a70b6cc8c26b";
+const SHIELD_MAINNET: &str =
+    "ps1qqqsyqcyq5rqwzqfpg9scrgwpugpzysnzs23v9ccrydpk8qarc0jqgfzyvjz2f389q5j5cvdrfg";
+const SHIELD_TESTNET: &str =
+    "ptestsapling1qqqsyqcyq5rqwzqfpg9scrgwpugpzysnzs23v9ccrydpk8qarc0jqgfzyvjz2f389q5j59t9m5a";
+
+fn hash() -> Vec<u8> {
+    hex::decode(HASH).unwrap()
+}
+
+mod decoding {
+    use super::*;
+
+    #[test]
+    fn decodes_transparent_addresses() {
+        let cases = [
+            (
+                "DDU6BCfxp2eGdQ5AuoyL4QQo6D4abms5qg",
+                AddressType::PubKeyHash,
+                Network::Mainnet,
+            ),
+            (
+                "6NiqSMc4jxmNj2hhVgJupGnRPdgYZ9VPmm",
+                AddressType::ScriptHash,
+                Network::Mainnet,
+            ),
+            (
+                "SVczfnWUEywBchg2iexr4YPkrrZi6aiv14",
+                AddressType::Staking,
+                Network::Mainnet,
+            ),
+            (
+                "EXMKLh2d1jUdovx4xfF7Tye8uTSHvz3QUjBX",
+                AddressType::Exchange,
+                Network::Mainnet,
+            ),
+            (
+                "y5JqW3ANCh9mmeGcaYH4v55YhC9Q82kuNn",
+                AddressType::PubKeyHash,
+                Network::Testnet,
+            ),
+            (
+                "8nkTM1Po13YdddYDeCJpj2R9AfECqbMdGY",
+                AddressType::ScriptHash,
+                Network::Testnet,
+            ),
+            (
+                "WX12WsVMLnZwo34txrJ2uo7d9u99CT8J3w",
+                AddressType::Staking,
+                Network::Testnet,
+            ),
+            (
+                "EXTMQjoFV48Lm5Da3xcQTkv1zFsro8kARmLM",
+                AddressType::Exchange,
+                Network::Testnet,
+            ),
+        ];
+        for (encoded, address_type, network) in cases {
+            let address = Address::decode(encoded).unwrap();
+            assert_eq!(address, Address::new(address_type, network, &hash()));
+            assert_eq!(address.encode(), encoded);
+        }
+    }
+
+    #[test]
+    fn decodes_shield_addresses() {
+        let payload: Vec<u8> = (0..43).collect();
+        for (encoded, network) in [
+            (SHIELD_MAINNET, Network::Mainnet),
+            (SHIELD_TESTNET, Network::Testnet),
+        ] {
+            let address = Address::decode(encoded).unwrap();
+            assert_eq!(
+                address,
+                Address::new(AddressType::Shield, network, &payload)
+            );
+            assert_eq!(address.encode(), encoded);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        // Wrong checksum
+        assert_eq!(Address::decode("DDU6BCfxp2eGdQ5AuoyL4QQo6D4abms5qh"), None);
+        assert_eq!(
+            Address::decode(
+                "ps1qqqsyqcyq5rqwzqfpg9scrgwpugpzysnzs23v9ccrydpk8qarc0jqgfzyvjz2f389q5j5cvdrfq"
+            ),
+            None
+        );
+        // Unknown hrp
+        assert_eq!(
+            Address::decode("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+            None
+        );
+        // Bitcoin address, valid checksum but unknown prefix
+        assert_eq!(Address::decode("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2"), None);
+        assert_eq!(Address::decode("address1"), None);
+        assert_eq!(Address::decode(""), None);
+    }
+}
+
+mod script_pub_key {
+    use super::*;
+
+    #[test]
+    fn converts_to_script_pub_key() {
+        let script = |address: &str| {
+            Address::decode(address)
+                .unwrap()
+                .to_script_pub_key()
+                .map(hex::encode)
+        };
+        assert_eq!(
+            script("DDU6BCfxp2eGdQ5AuoyL4QQo6D4abms5qg"),
+            Some(format!("76a914{}88ac", HASH))
+        );
+        assert_eq!(
+            script("6NiqSMc4jxmNj2hhVgJupGnRPdgYZ9VPmm"),
+            Some(format!("a914{}87", HASH))
+        );
+        assert_eq!(
+            script("EXMKLh2d1jUdovx4xfF7Tye8uTSHvz3QUjBX"),
+            Some(format!("e076a914{}88ac", HASH))
+        );
+        assert_eq!(script("SVczfnWUEywBchg2iexr4YPkrrZi6aiv14"), None);
+        assert_eq!(script(SHIELD_MAINNET), None);
+    }
+
+    #[test]
+    fn converts_from_script_pub_key() {
+        let address = |script: &str, network| {
+            Address::from_script_pub_key(&hex::decode(script).unwrap(), network)
+                .map(|address| address.encode())
+        };
+        assert_eq!(
+            address(&format!("76a914{}88ac", HASH), Network::Mainnet),
+            Some("DDU6BCfxp2eGdQ5AuoyL4QQo6D4abms5qg".to_owned())
+        );
+        assert_eq!(
+            address(&format!("76a914{}88ac", HASH), Network::Testnet),
+            Some("y5JqW3ANCh9mmeGcaYH4v55YhC9Q82kuNn".to_owned())
+        );
+        assert_eq!(
+            address(&format!("e076a914{}88ac", HASH), Network::Mainnet),
+            Some("EXMKLh2d1jUdovx4xfF7Tye8uTSHvz3QUjBX".to_owned())
+        );
+        assert_eq!(address("6a0568656c6c6f", Network::Mainnet), None);
+    }
+}
+
+#[test]
+fn pairs_exchange_addresses() {
+    let pair = |address: &str| {
+        Address::decode(address)
+            .unwrap()
+            .get_exchange_pair()
+            .map(|address| address.encode())
+    };
+    assert_eq!(
+        pair("EXMKLh2d1jUdovx4xfF7Tye8uTSHvz3QUjBX"),
+        Some("DDU6BCfxp2eGdQ5AuoyL4QQo6D4abms5qg".to_owned())
+    );
+    assert_eq!(
+        pair("DDU6BCfxp2eGdQ5AuoyL4QQo6D4abms5qg"),
+        Some("EXMKLh2d1jUdovx4xfF7Tye8uTSHvz3QUjBX".to_owned())
+    );
+    assert_eq!(
+        pair("y5JqW3ANCh9mmeGcaYH4v55YhC9Q82kuNn"),
+        Some("EXTMQjoFV48Lm5Da3xcQTkv1zFsro8kARmLM".to_owned())
+    );
+    // Staking addresses have no exchange form
+    assert_eq!(pair("SVczfnWUEywBchg2iexr4YPkrrZi6aiv14"), None);
+}
+
+#[test]
+fn validates_addresses() {
+    assert_eq!(
+        AddressInfo::new("EXMKLh2d1jUdovx4xfF7Tye8uTSHvz3QUjBX"),
+        AddressInfo {
+            is_valid: true,
+            address_type: Some(AddressType::Exchange),
+            network: Some(Network::Mainnet),
+            script_pub_key: Some(format!("e076a914{}88ac", HASH)),
+        }
+    );
+    assert_eq!(
+        AddressInfo::new(SHIELD_TESTNET),
+        AddressInfo {
+            is_valid: true,
+            address_type: Some(AddressType::Shield),
+            network: Some(Network::Testnet),
+            script_pub_key: None,
+        }
+    );
+    assert_eq!(
+        AddressInfo::new("notanaddress"),
+        AddressInfo {
+            is_valid: false,
+            address_type: None,
+            network: None,
+            script_pub_key: None,
+        }
+    );
+}
@@ -1,8 +1,9 @@
 use super::types::Block;
 use futures::stream::Stream;
-use std::{ops::Deref, pin::Pin, sync::Arc};
+use std::{future::Future, ops::Deref, pin::Pin, sync::Arc};
 
 pub type PinnedStream<'a, T> = Pin<Box<dyn Stream<Item = T> + 'a + Send>>;
+pub type PinnedFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a + Send>>;
 pub type Bs = Arc<dyn BlockSource + 'static + Send + Sync>;
 pub type Ibs = Arc<dyn IndexedBlockSource + 'static + Send + Sync>;
 
@@ -38,12 +39,20 @@ pub trait BlockSource {
 pub trait IndexedBlockSource: BlockSource {
     /**
      * Returns a stream of blocks with associated block count.
-     * Stream must be sorted by block count
+     * Stream must be sorted by block count, and wait for new blocks once it reaches the tip
      */
     fn get_blocks_indexed(
         &self,
         start_from: u64,
     ) -> crate::error::Result<PinnedStream<'_, (Block, u64)>>;
+    /**
+     * Returns the height of the tip of the source
+     */
+    fn get_block_count(&self) -> PinnedFuture<'_, crate::error::Result<u64>>;
+    /**
+     * Returns the hash of the block at `height`, which must not be above the tip
+     */
+    fn get_block_hash(&self, height: u64) -> PinnedFuture<'_, crate::error::Result<String>>;
     fn as_block_source(&self) -> &(dyn BlockSource + Send + Sync + 'static);
 }
 
@@ -51,6 +60,8 @@ pub trait IndexedBlockSource: BlockSource {
 pub mod test {
     use super::super::types::{test::get_test_blocks, Block};
     use super::*;
+    use crate::error::PIVXErrors;
+    use futures::StreamExt;
 
     pub struct MockBlockSource;
 
@@ -61,4 +72,169 @@ pub mod test {
             Ok(Box::pin(futures::stream::iter(get_test_blocks())))
         }
     }
+
+    fn get_test_block_hash(first_height: u64, height: u64) -> crate::error::Result<String> {
+        height
+            .checked_sub(first_height)
+            .and_then(|index| get_test_blocks().into_iter().nth(index as usize))
+            .map(|block| block.hash)
+            .ok_or(PIVXErrors::InvalidBlock)
+    }
+
+    /// Returns the test blocks, with heights starting from `first_height`
+    pub struct MockIndexedBlockSource {
+        pub first_height: u64,
+    }
+
+    impl BlockSource for MockIndexedBlockSource {
+        fn get_blocks(&self) -> crate::error::Result<PinnedStream<'_, Block>> {
+            Ok(Box::pin(
+                self.get_blocks_indexed(0)?.map(|(block, _)| block),
+            ))
+        }
+
+        fn instantiate(self) -> BlockSourceType {
+            BlockSourceType::Indexed(Arc::new(self))
+        }
+    }
+
+    impl IndexedBlockSource for MockIndexedBlockSource {
+        fn get_blocks_indexed(
+            &self,
+            start_from: u64,
+        ) -> crate::error::Result<PinnedStream<'_, (Block, u64)>> {
+            let blocks = get_test_blocks()
+                .into_iter()
+                .zip(self.first_height..)
+                .filter(move |(_, height)| *height > start_from)
+                .map(|(mut block, height)| {
+                    block.height = Some(height);
+                    (block, height)
+                });
+            Ok(Box::pin(futures::stream::iter(blocks)))
+        }
+
+        fn get_block_count(&self) -> PinnedFuture<'_, crate::error::Result<u64>> {
+            let block_count = self.first_height + get_test_blocks().len() as u64 - 1;
+            Box::pin(futures::future::ready(Ok(block_count)))
+        }
+
+        fn get_block_hash(&self, height: u64) -> PinnedFuture<'_, crate::error::Result<String>> {
+            let hash = get_test_block_hash(self.first_height, height);
+            Box::pin(futures::future::ready(hash))
+        }
+
+        fn as_block_source(&self) -> &(dyn BlockSource + Send + Sync + 'static) {
+            self
+        }
+    }
+
+    /// Returns `blocks` with heights from `first_height`, which are also set on their txs
+    pub struct MockChain {
+        blocks: Vec<Block>,
+    }
+
+    impl MockChain {
+        pub fn new(first_height: u64, blocks: Vec<Block>) -> Self {
+            let blocks = blocks
+                .into_iter()
+                .zip(first_height..)
+                .map(|(mut block, height)| {
+                    block.height = Some(height);
+                    for tx in &mut block.txs {
+                        tx.height = Some(height);
+                    }
+                    block
+                })
+                .collect();
+            Self { blocks }
+        }
+    }
+
+    impl BlockSource for MockChain {
+        fn get_blocks(&self) -> crate::error::Result<PinnedStream<'_, Block>> {
+            Ok(Box::pin(
+                self.get_blocks_indexed(0)?.map(|(block, _)| block),
+            ))
+        }
+
+        fn instantiate(self) -> BlockSourceType {
+            BlockSourceType::Indexed(Arc::new(self))
+        }
+    }
+
+    impl IndexedBlockSource for MockChain {
+        fn get_blocks_indexed(
+            &self,
+            start_from: u64,
+        ) -> crate::error::Result<PinnedStream<'_, (Block, u64)>> {
+            let blocks = self
+                .blocks
+                .iter()
+                .filter_map(|block| Some((block.clone(), block.height?)))
+                .filter(move |(_, height)| *height > start_from);
+            Ok(Box::pin(futures::stream::iter(blocks)))
+        }
+
+        fn get_block_count(&self) -> PinnedFuture<'_, crate::error::Result<u64>> {
+            let block_count = self.blocks.last().and_then(|block| block.height);
+            Box::pin(futures::future::ready(Ok(block_count.unwrap_or(0))))
+        }
+
+        fn get_block_hash(&self, height: u64) -> PinnedFuture<'_, crate::error::Result<String>> {
+            let hash = self
+                .blocks
+                .iter()
+                .find(|block| block.height == Some(height))
+                .map(|block| block.hash.clone())
+                .ok_or(PIVXErrors::InvalidBlock);
+            Box::pin(futures::future::ready(hash))
+        }
+
+        fn as_block_source(&self) -> &(dyn BlockSource + Send + Sync + 'static) {
+            self
+        }
+    }
+
+    /// Returns the test blocks from height 1, then waits forever like a node at the chain tip
+    pub struct MockStalledBlockSource;
+
+    impl BlockSource for MockStalledBlockSource {
+        fn get_blocks(&self) -> crate::error::Result<PinnedStream<'_, Block>> {
+            Ok(Box::pin(
+                self.get_blocks_indexed(0)?.map(|(block, _)| block),
+            ))
+        }
+
+        fn instantiate(self) -> BlockSourceType {
+            BlockSourceType::Indexed(Arc::new(self))
+        }
+    }
+
+    impl IndexedBlockSource for MockStalledBlockSource {
+        fn get_blocks_indexed(
+            &self,
+            start_from: u64,
+        ) -> crate::error::Result<PinnedStream<'_, (Block, u64)>> {
+            let blocks = get_test_blocks()
+                .into_iter()
+                .zip(1..)
+                .filter(move |(_, height)| *height > start_from);
+            Ok(Box::pin(
+                futures::stream::iter(blocks).chain(futures::stream::pending()),
+            ))
+        }
+
+        fn get_block_count(&self) -> PinnedFuture<'_, crate::error::Result<u64>> {
+            Box::pin(futures::future::ready(Ok(get_test_blocks().len() as u64)))
+        }
+
+        fn get_block_hash(&self, height: u64) -> PinnedFuture<'_, crate::error::Result<String>> {
+            Box::pin(futures::future::ready(get_test_block_hash(1, height)))
+        }
+
+        fn as_block_source(&self) -> &(dyn BlockSource + Send + Sync + 'static) {
+            self
+        }
+    }
 }
@@ -1,10 +1,64 @@
 use futures::Future;
+use std::path::PathBuf;
 
-use super::types::{Tx, Vin};
+use super::types::{
+    BlockHeader, CompactBlock, Delegation, RawTx, SaplingTree, SpentNullifier, Tx, Vin,
+};
 
 pub trait Database {
     async fn get_txid_from_vin(&self, vin: &Vin) -> crate::error::Result<Option<String>>;
+    /**
+     * Returns the txids of `address` once each, in the order they were first stored
+     */
     async fn get_address_txids(&self, address: &str) -> crate::error::Result<Vec<String>>;
+    /**
+     * Returns the unspent cold stake outputs delegated to `staker`
+     */
+    async fn get_delegations(&self, staker: &str) -> crate::error::Result<Vec<Delegation>>;
+    /**
+     * Returns the compact blocks with height in `from..=to`, sorted by height.
+     * Blocks without shield transactions are not stored, so they are skipped
+     */
+    async fn get_compact_blocks(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> crate::error::Result<Vec<CompactBlock>>;
+    /**
+     * Stores the compact blocks and indexes their nullifiers
+     */
+    async fn store_compact_blocks<I>(&mut self, blocks: I) -> crate::error::Result<()>
+    where
+        I: Iterator<Item = CompactBlock>;
+    /**
+     * Returns the nullifiers among `nullifiers` that have been spent.
+     * Unspent nullifiers are not returned
+     */
+    async fn get_spent_nullifiers(
+        &self,
+        nullifiers: &[&str],
+    ) -> crate::error::Result<Vec<SpentNullifier>>;
+    /**
+     * Returns the last sapling tree snapshot at or below `height`
+     */
+    async fn get_sapling_tree(&self, height: u64) -> crate::error::Result<Option<SaplingTree>>;
+    async fn store_sapling_trees<I>(&mut self, trees: I) -> crate::error::Result<()>
+    where
+        I: Iterator<Item = SaplingTree>;
+    async fn get_header(&self, height: u64) -> crate::error::Result<Option<BlockHeader>>;
+    async fn get_header_by_hash(&self, hash: &str) -> crate::error::Result<Option<BlockHeader>>;
+    /**
+     * Returns the header of the latest block mined at or before `time`
+     */
+    async fn get_header_at_time(&self, time: u64) -> crate::error::Result<Option<BlockHeader>>;
+    /**
+     * Stores the headers, invalidating the cached raw txs of blocks that were reorged out
+     */
+    async fn store_headers<I>(&mut self, headers: I) -> crate::error::Result<()>
+    where
+        I: Iterator<Item = BlockHeader>;
+    async fn get_raw_tx(&self, txid: &str) -> crate::error::Result<Option<RawTx>>;
+    async fn store_raw_tx(&mut self, raw_tx: &RawTx) -> crate::error::Result<()>;
     async fn store_tx(&mut self, tx: &Tx) -> crate::error::Result<()>;
     /**
      * Override if there is a more efficient way to store multiple txs at the same time
@@ -19,9 +73,21 @@ pub trait Database {
         Ok(())
     }
 
+    /**
+     * Override if there is a more efficient way to sum the delegations
+     */
+    async fn get_delegated_balance(&self, staker: &str) -> crate::error::Result<u64> {
+        Ok(self
+            .get_delegations(staker)
+            .await?
+            .iter()
+            .map(|delegation| delegation.value)
+            .sum())
+    }
+
     /**
      * Update block count lower bound, if available.
-     * Must not be called with block_count lower than a previous call
+     * Calls with a block_count lower than a previous call must be ignored
      */
     async fn update_block_count(&mut self, _block_count: u64) -> crate::error::Result<()> {
         Ok(())
@@ -33,17 +99,58 @@ pub trait Database {
     fn get_last_indexed_block(&self) -> impl Future<Output = crate::error::Result<u64>> {
         async { Ok(0) }
     }
+
+    /**
+     * Deletes the headers, compact blocks, nullifiers, sapling trees, cached raw txs
+     * and the address, vin and delegation rows of txs above `height`,
+     * and sets the block count to `height` even if it's lower.
+     * Rows of txs stored without a height are kept
+     */
+    async fn rewind(&mut self, height: u64) -> crate::error::Result<()>;
+
+    /**
+     * Deletes all indexed data, including the block count
+     */
+    async fn wipe(&mut self) -> crate::error::Result<()>;
+
+    /**
+     * Writes a consistent copy of all indexed data to the file at `path`
+     */
+    async fn backup(&self, path: PathBuf) -> crate::error::Result<()>;
+
+    /**
+     * Replaces all indexed data with the file at `path`,
+     * which must have been written by `backup` of the same implementation
+     */
+    async fn restore(&mut self, path: PathBuf) -> crate::error::Result<()>;
 }
 
 #[cfg(test)]
 pub mod test {
     use super::*;
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, HashMap};
 
-    #[derive(Default)]
+    #[derive(Default, Clone)]
     pub struct MockDB {
         address_map: HashMap<String, Vec<String>>,
         vin_map: HashMap<Vin, String>,
+        delegations: Vec<Delegation>,
+        compact_blocks: BTreeMap<u64, CompactBlock>,
+        sapling_trees: BTreeMap<u64, SaplingTree>,
+        headers: BTreeMap<u64, BlockHeader>,
+        raw_txs: HashMap<String, RawTx>,
+        /// Height of the stored txs, so that they can be rewound
+        tx_heights: HashMap<String, u64>,
+        block_count: u64,
+        /// Tx whose write fails, see `FaultInjection`
+        failing_txid: Option<String>,
+    }
+
+    impl conformance::FaultInjection for MockDB {
+        async fn fail_writes_of(&mut self, txid: &str) -> crate::error::Result<()> {
+            self.failing_txid = Some(txid.to_owned());
+            Ok(())
+        }
     }
 
     impl Database for MockDB {
@@ -51,17 +158,821 @@ pub mod test {
             Ok(self.address_map.get(address).unwrap_or(&vec![]).clone())
         }
 
+        async fn get_delegations(&self, staker: &str) -> crate::error::Result<Vec<Delegation>> {
+            Ok(self
+                .delegations
+                .iter()
+                .filter(|d| d.staker == staker)
+                .filter(|d| {
+                    !self.vin_map.contains_key(&Vin {
+                        txid: d.txid.clone(),
+                        n: d.n,
+                    })
+                })
+                .cloned()
+                .collect())
+        }
+
+        async fn get_compact_blocks(
+            &self,
+            from: u64,
+            to: u64,
+        ) -> crate::error::Result<Vec<CompactBlock>> {
+            if from > to {
+                return Ok(vec![]);
+            }
+            Ok(self
+                .compact_blocks
+                .range(from..=to)
+                .map(|(_, block)| block.clone())
+                .collect())
+        }
+
+        async fn store_compact_blocks<I>(&mut self, blocks: I) -> crate::error::Result<()>
+        where
+            I: Iterator<Item = CompactBlock>,
+        {
+            for block in blocks {
+                self.compact_blocks.insert(block.height, block);
+            }
+            Ok(())
+        }
+
+        async fn get_spent_nullifiers(
+            &self,
+            nullifiers: &[&str],
+        ) -> crate::error::Result<Vec<SpentNullifier>> {
+            Ok(self
+                .compact_blocks
+                .values()
+                .flat_map(|block| block.get_spent_nullifiers())
+                .filter(|spent| nullifiers.contains(&spent.nullifier.as_str()))
+                .collect())
+        }
+
+        async fn get_sapling_tree(&self, height: u64) -> crate::error::Result<Option<SaplingTree>> {
+            Ok(self
+                .sapling_trees
+                .range(..=height)
+                .next_back()
+                .map(|(_, tree)| tree.clone()))
+        }
+
+        async fn store_sapling_trees<I>(&mut self, trees: I) -> crate::error::Result<()>
+        where
+            I: Iterator<Item = SaplingTree>,
+        {
+            for tree in trees {
+                self.sapling_trees.insert(tree.height, tree);
+            }
+            Ok(())
+        }
+
+        async fn get_header(&self, height: u64) -> crate::error::Result<Option<BlockHeader>> {
+            Ok(self.headers.get(&height).cloned())
+        }
+
+        async fn get_header_by_hash(
+            &self,
+            hash: &str,
+        ) -> crate::error::Result<Option<BlockHeader>> {
+            Ok(self.headers.values().find(|h| h.hash == hash).cloned())
+        }
+
+        async fn get_header_at_time(&self, time: u64) -> crate::error::Result<Option<BlockHeader>> {
+            Ok(self
+                .headers
+                .values()
+                .filter(|h| h.time <= time)
+                .max_by_key(|h| h.time)
+                .cloned())
+        }
+
+        async fn store_headers<I>(&mut self, headers: I) -> crate::error::Result<()>
+        where
+            I: Iterator<Item = BlockHeader>,
+        {
+            for header in headers {
+                self.raw_txs.retain(|_, raw_tx| {
+                    raw_tx.height != header.height || raw_tx.block_hash == header.hash
+                });
+                self.headers.insert(header.height, header);
+            }
+            Ok(())
+        }
+
+        async fn get_raw_tx(&self, txid: &str) -> crate::error::Result<Option<RawTx>> {
+            Ok(self.raw_txs.get(txid).cloned())
+        }
+
+        async fn store_raw_tx(&mut self, raw_tx: &RawTx) -> crate::error::Result<()> {
+            self.raw_txs.insert(raw_tx.txid.clone(), raw_tx.clone());
+            Ok(())
+        }
+
         async fn store_tx(&mut self, tx: &Tx) -> crate::error::Result<()> {
-            for address in &tx.addresses {
-                self.address_map
-                    .entry(address.clone())
-                    .and_modify(|vec| vec.push(tx.txid.clone()))
-                    .or_insert(vec![tx.txid.clone()]);
+            let stakers = tx.delegations.iter().map(|d| &d.staker);
+            for address in tx.addresses.iter().chain(stakers) {
+                let txids = self.address_map.entry(address.clone()).or_default();
+                if !txids.contains(&tx.txid) {
+                    txids.push(tx.txid.clone());
+                }
+            }
+            for vin in &tx.vin {
+                self.vin_map.insert(vin.clone(), tx.txid.clone());
+            }
+            for delegation in &tx.delegations {
+                if !self.delegations.contains(delegation) {
+                    self.delegations.push(delegation.clone());
+                }
+            }
+            if let Some(height) = tx.height {
+                self.tx_heights.entry(tx.txid.clone()).or_insert(height);
+            }
+            Ok(())
+        }
+
+        async fn store_txs<I>(&mut self, txs: I) -> crate::error::Result<()>
+        where
+            I: Iterator<Item = Tx>,
+        {
+            // Like a db transaction, the txs stored before a failure are rolled back
+            let rollback = self.clone();
+            for tx in txs {
+                self.store_tx(&tx).await?;
+                if self.failing_txid.as_ref() == Some(&tx.txid) {
+                    *self = rollback;
+                    return Err(std::io::Error::other("Injected write failure").into());
+                }
             }
             Ok(())
         }
+
+        async fn update_block_count(&mut self, block_count: u64) -> crate::error::Result<()> {
+            self.block_count = self.block_count.max(block_count);
+            Ok(())
+        }
+
+        async fn get_last_indexed_block(&self) -> crate::error::Result<u64> {
+            Ok(self.block_count)
+        }
+
         async fn get_txid_from_vin(&self, vin: &Vin) -> crate::error::Result<Option<String>> {
             Ok(self.vin_map.get(vin).cloned())
         }
+
+        async fn rewind(&mut self, height: u64) -> crate::error::Result<()> {
+            self.compact_blocks.split_off(&(height + 1));
+            self.sapling_trees.split_off(&(height + 1));
+            self.headers.split_off(&(height + 1));
+            self.raw_txs.retain(|_, raw_tx| raw_tx.height <= height);
+            let rewound: Vec<_> = self
+                .tx_heights
+                .iter()
+                .filter(|(_, tx_height)| **tx_height > height)
+                .map(|(txid, _)| txid.clone())
+                .collect();
+            for txids in self.address_map.values_mut() {
+                txids.retain(|txid| !rewound.contains(txid));
+            }
+            self.address_map.retain(|_, txids| !txids.is_empty());
+            self.vin_map.retain(|_, txid| !rewound.contains(txid));
+            self.delegations.retain(|d| !rewound.contains(&d.txid));
+            self.tx_heights.retain(|_, tx_height| *tx_height <= height);
+            self.block_count = height;
+            Ok(())
+        }
+
+        async fn wipe(&mut self) -> crate::error::Result<()> {
+            *self = Self::default();
+            Ok(())
+        }
+
+        async fn backup(&self, path: PathBuf) -> crate::error::Result<()> {
+            // Vin keys are not strings, so the map is stored as pairs
+            let vins: Vec<_> = self.vin_map.iter().collect();
+            let data = (
+                &self.address_map,
+                vins,
+                &self.delegations,
+                &self.compact_blocks,
+                &self.sapling_trees,
+                &self.headers,
+                &self.raw_txs,
+                &self.tx_heights,
+                self.block_count,
+            );
+            std::fs::write(path, serde_json::to_vec(&data)?)?;
+            Ok(())
+        }
+
+        async fn restore(&mut self, path: PathBuf) -> crate::error::Result<()> {
+            let (
+                address_map,
+                vins,
+                delegations,
+                compact_blocks,
+                sapling_trees,
+                headers,
+                raw_txs,
+                tx_heights,
+                block_count,
+            ): (_, Vec<(Vin, String)>, _, _, _, _, _, _, _) =
+                serde_json::from_slice(&std::fs::read(path)?)?;
+            *self = Self {
+                address_map,
+                vin_map: vins.into_iter().collect(),
+                delegations,
+                compact_blocks,
+                sapling_trees,
+                headers,
+                raw_txs,
+                tx_heights,
+                block_count,
+                failing_txid: None,
+            };
+            Ok(())
+        }
+    }
+}
+
+/// Checks that every `Database` implementation must pass.
+/// Use `conformance_tests!` to run them against a backend
+#[cfg(test)]
+pub mod conformance {
+    use super::super::types::test::get_test_blocks;
+    use super::super::types::{BlockHeader, RawTx, SaplingTree, SpentNullifier};
+    use super::*;
+
+    /// Makes a backend fail in the middle of a write, to check that it's rolled back
+    pub trait FaultInjection {
+        /**
+         * Makes storing the tx `txid` fail, after the txs before it in the batch were written
+         */
+        async fn fail_writes_of(&mut self, txid: &str) -> crate::error::Result<()>;
+    }
+
+    /**
+     * Generates a test for each check.
+     * `$new_database` is an async fn returning a guard to keep alive during the test
+     * (e.g. a temporary directory) and an empty database
+     */
+    macro_rules! conformance_tests {
+        ($new_database:path) => {
+            $crate::address_index::database::conformance::conformance_tests!(
+                @checks $new_database,
+                stores_txs,
+                stores_txs_batch,
+                it_indexes_delegations,
+                it_stores_compact_blocks,
+                it_stores_sapling_trees,
+                it_stores_headers,
+                it_invalidates_reorged_raw_txs,
+                it_stores_block_count,
+                it_keeps_block_count_monotonic,
+                it_ignores_duplicates,
+                it_keeps_txids_in_stored_order,
+                it_rolls_back_interrupted_batches,
+                it_replaces_reorged_spenders,
+                it_rewinds,
+                it_wipes,
+                it_backs_up_and_restores
+            );
+        };
+        (@checks $new_database:path, $($check:ident),*) => {
+            mod conformance {
+                use super::*;
+                $(
+                    #[tokio::test]
+                    async fn $check() -> crate::error::Result<()> {
+                        let (_guard, database) = $new_database().await?;
+                        $crate::address_index::database::conformance::$check(database).await
+                    }
+                )*
+            }
+        };
+    }
+    pub(crate) use conformance_tests;
+
+    pub async fn test_address_retrival<D: Database>(database: &D) -> crate::error::Result<()> {
+        assert_eq!(
+            database.get_address_txids("address1").await?,
+            vec!["txid1", "txid2", "txid3"]
+        );
+        assert_eq!(database.get_address_txids("address2").await?, vec!["txid1"]);
+        assert_eq!(database.get_address_txids("address4").await?, vec!["txid2"]);
+        assert_eq!(database.get_address_txids("address5").await?, vec!["txid3"]);
+        assert_eq!(
+            database.get_address_txids("address6").await?,
+            Vec::<String>::new()
+        );
+
+        assert_eq!(
+            database
+                .get_txid_from_vin(&Vin {
+                    txid: "spenttxid".to_owned(),
+                    n: 3
+                })
+                .await?,
+            Some("txid1".to_owned())
+        );
+
+        assert_eq!(
+            database
+                .get_txid_from_vin(&Vin {
+                    txid: "spenttxid".to_owned(),
+                    n: 1
+                })
+                .await?,
+            Some("txid2".to_owned())
+        );
+        assert_eq!(
+            database
+                .get_txid_from_vin(&Vin {
+                    txid: "spenttxid2".to_owned(),
+                    n: 5
+                })
+                .await?,
+            Some("txid2".to_owned())
+        );
+
+        assert_eq!(
+            database
+                .get_txid_from_vin(&Vin {
+                    txid: "spenttxid2".to_owned(),
+                    n: 0
+                })
+                .await?,
+            None
+        );
+
+        assert_eq!(
+            database
+                .get_txid_from_vin(&Vin {
+                    txid: "spenttxid5".to_owned(),
+                    n: 3
+                })
+                .await?,
+            None
+        );
+        Ok(())
+    }
+
+    pub async fn stores_txs<D: Database>(mut database: D) -> crate::error::Result<()> {
+        let test_blocks = get_test_blocks();
+        for block in test_blocks {
+            for tx in block.txs {
+                database.store_tx(&tx).await?;
+            }
+        }
+        test_address_retrival(&database).await?;
+        Ok(())
+    }
+
+    pub async fn stores_txs_batch<D: Database>(mut database: D) -> crate::error::Result<()> {
+        let test_blocks = get_test_blocks();
+        database
+            .store_txs(
+                test_blocks
+                    .into_iter()
+                    .flat_map(|block| block.txs.into_iter()),
+            )
+            .await?;
+
+        test_address_retrival(&database).await?;
+        Ok(())
+    }
+
+    pub async fn it_indexes_delegations<D: Database>(mut database: D) -> crate::error::Result<()> {
+        database
+            .store_txs(
+                get_test_blocks()
+                    .into_iter()
+                    .flat_map(|block| block.txs.into_iter()),
+            )
+            .await?;
+        // Stakers are indexed alongside owners
+        assert_eq!(
+            database.get_address_txids("staker1").await?,
+            vec!["txid1", "txid3"]
+        );
+        // The delegation in txid1 is spent by txid2
+        assert_eq!(
+            database.get_delegations("staker1").await?,
+            vec![Delegation {
+                txid: "txid3".to_owned(),
+                n: 0,
+                value: 250,
+                staker: "staker1".to_owned(),
+                owner: "address5".to_owned(),
+            }]
+        );
+        assert_eq!(database.get_delegated_balance("staker1").await?, 250);
+        assert_eq!(database.get_delegated_balance("staker2").await?, 50);
+        assert_eq!(database.get_delegated_balance("address1").await?, 0);
+        assert_eq!(database.get_delegations("address1").await?, vec![]);
+        Ok(())
     }
+
+    pub async fn it_stores_compact_blocks<D: Database>(
+        mut database: D,
+    ) -> crate::error::Result<()> {
+        let compact_blocks: Vec<_> = get_test_blocks()
+            .iter()
+            .filter_map(|block| block.to_compact())
+            .collect();
+        assert_eq!(compact_blocks.len(), 1);
+        database
+            .store_compact_blocks(compact_blocks.clone().into_iter())
+            .await?;
+        assert_eq!(database.get_compact_blocks(1, 3).await?, compact_blocks);
+        assert_eq!(database.get_compact_blocks(2, 2).await?, compact_blocks);
+        assert_eq!(database.get_compact_blocks(3, 100).await?, vec![]);
+        // Empty range
+        assert_eq!(database.get_compact_blocks(3, 2).await?, vec![]);
+        assert_eq!(
+            database
+                .get_spent_nullifiers(&["nullifier0", "nullifier1"])
+                .await?,
+            vec![SpentNullifier {
+                nullifier: "nullifier1".to_owned(),
+                txid: "txid2".to_owned(),
+                height: 2,
+            }]
+        );
+        Ok(())
+    }
+
+    pub async fn it_stores_sapling_trees<D: Database>(mut database: D) -> crate::error::Result<()> {
+        let tree = |height: u64| SaplingTree {
+            height,
+            root: format!("root{}", height),
+            tree: format!("tree{}", height),
+        };
+        database
+            .store_sapling_trees(vec![tree(1000), tree(2000)].into_iter())
+            .await?;
+        assert_eq!(database.get_sapling_tree(999).await?, None);
+        assert_eq!(database.get_sapling_tree(1000).await?, Some(tree(1000)));
+        assert_eq!(database.get_sapling_tree(1999).await?, Some(tree(1000)));
+        assert_eq!(database.get_sapling_tree(5000).await?, Some(tree(2000)));
+        Ok(())
+    }
+
+    pub async fn it_stores_headers<D: Database>(mut database: D) -> crate::error::Result<()> {
+        let headers: Vec<_> = get_test_blocks()
+            .iter()
+            .filter_map(|block| block.to_header())
+            .collect();
+        database.store_headers(headers.clone().into_iter()).await?;
+        assert_eq!(database.get_header(2).await?, Some(headers[1].clone()));
+        assert_eq!(database.get_header(4).await?, None);
+        assert_eq!(
+            database.get_header_by_hash("hash3").await?,
+            Some(headers[2].clone())
+        );
+        assert_eq!(database.get_header_by_hash("hash4").await?, None);
+        assert_eq!(
+            database.get_header_at_time(1059).await?,
+            Some(headers[0].clone())
+        );
+        assert_eq!(
+            database.get_header_at_time(1060).await?,
+            Some(headers[1].clone())
+        );
+        assert_eq!(database.get_header_at_time(999).await?, None);
+        Ok(())
+    }
+
+    pub async fn it_invalidates_reorged_raw_txs<D: Database>(
+        mut database: D,
+    ) -> crate::error::Result<()> {
+        let headers: Vec<_> = get_test_blocks()
+            .iter()
+            .filter_map(|block| block.to_header())
+            .collect();
+        database.store_headers(headers.clone().into_iter()).await?;
+        let raw_tx = |txid: &str, header: &BlockHeader| RawTx {
+            txid: txid.to_owned(),
+            hex: format!("hex{}", txid),
+            block_hash: header.hash.clone(),
+            height: header.height,
+            time: header.time,
+        };
+        database.store_raw_tx(&raw_tx("txid1", &headers[0])).await?;
+        database.store_raw_tx(&raw_tx("txid2", &headers[1])).await?;
+        assert_eq!(
+            database.get_raw_tx("txid2").await?,
+            Some(raw_tx("txid2", &headers[1]))
+        );
+        // Storing the same header again doesn't invalidate anything
+        database.store_headers(headers.clone().into_iter()).await?;
+        assert!(database.get_raw_tx("txid2").await?.is_some());
+
+        let mut reorged = headers[1].clone();
+        reorged.hash = "hash2b".to_owned();
+        database.store_headers(std::iter::once(reorged)).await?;
+        assert_eq!(database.get_raw_tx("txid2").await?, None);
+        assert!(database.get_raw_tx("txid1").await?.is_some());
+        Ok(())
+    }
+
+    pub async fn it_stores_block_count<D: Database>(mut database: D) -> crate::error::Result<()> {
+        // Last indexed block should be 0 initially
+        assert_eq!(database.get_last_indexed_block().await?, 0);
+        database.update_block_count(1345).await?;
+        // Last indexed block should update
+        assert_eq!(database.get_last_indexed_block().await?, 1345);
+        database.update_block_count(13450).await?;
+        // Last indexed block should update even if there is already a row
+        assert_eq!(database.get_last_indexed_block().await?, 13450);
+
+        Ok(())
+    }
+
+    pub async fn it_keeps_block_count_monotonic<D: Database>(
+        mut database: D,
+    ) -> crate::error::Result<()> {
+        database.update_block_count(100).await?;
+        database.update_block_count(50).await?;
+        assert_eq!(database.get_last_indexed_block().await?, 100);
+        database.update_block_count(100).await?;
+        assert_eq!(database.get_last_indexed_block().await?, 100);
+        Ok(())
+    }
+
+    /// Blocks can be indexed more than once, e.g. if the app is closed before
+    /// the block count is updated
+    pub async fn it_ignores_duplicates<D: Database>(mut database: D) -> crate::error::Result<()> {
+        for _ in 0..2 {
+            let blocks = get_test_blocks();
+            let compact_blocks: Vec<_> = blocks.iter().filter_map(|b| b.to_compact()).collect();
+            let headers: Vec<_> = blocks.iter().filter_map(|b| b.to_header()).collect();
+            database
+                .store_compact_blocks(compact_blocks.into_iter())
+                .await?;
+            database.store_headers(headers.into_iter()).await?;
+            database
+                .store_txs(blocks.into_iter().flat_map(|block| block.txs.into_iter()))
+                .await?;
+        }
+        test_address_retrival(&database).await?;
+        assert_eq!(
+            database.get_address_txids("staker1").await?,
+            vec!["txid1", "txid3"]
+        );
+        assert_eq!(database.get_delegations("staker2").await?.len(), 1);
+        assert_eq!(database.get_delegated_balance("staker1").await?, 250);
+        assert_eq!(database.get_compact_blocks(0, 10).await?.len(), 1);
+        assert_eq!(
+            database.get_spent_nullifiers(&["nullifier1"]).await?.len(),
+            1
+        );
+        assert_eq!(
+            database.get_header_at_time(1060).await?.map(|h| h.height),
+            Some(2)
+        );
+        Ok(())
+    }
+
+    pub async fn it_keeps_txids_in_stored_order<D: Database>(
+        mut database: D,
+    ) -> crate::error::Result<()> {
+        let tx = |txid: &str, height: u64| Tx {
+            txid: txid.to_owned(),
+            height: Some(height),
+            addresses: vec!["address1".to_owned()],
+            vin: vec![],
+            // The owner also stakes, the txid is still listed once
+            delegations: vec![Delegation {
+                txid: txid.to_owned(),
+                n: 0,
+                value: 10,
+                staker: "address1".to_owned(),
+                owner: "address1".to_owned(),
+            }],
+            shield_spends: vec![],
+            shield_outputs: vec![],
+        };
+        database
+            .store_txs(vec![tx("txidc", 1), tx("txida", 1)].into_iter())
+            .await?;
+        database.store_txs(std::iter::once(tx("txidb", 2))).await?;
+        // Storing a tx again doesn't move it
+        database.store_tx(&tx("txida", 1)).await?;
+        assert_eq!(
+            database.get_address_txids("address1").await?,
+            vec!["txidc", "txida", "txidb"]
+        );
+        database.rewind(1).await?;
+        database.store_txs(std::iter::once(tx("txidd", 2))).await?;
+        assert_eq!(
+            database.get_address_txids("address1").await?,
+            vec!["txidc", "txida", "txidd"]
+        );
+        Ok(())
+    }
+
+    pub async fn it_rolls_back_interrupted_batches<D: Database + FaultInjection>(
+        mut database: D,
+    ) -> crate::error::Result<()> {
+        let txs = get_test_blocks()
+            .into_iter()
+            .flat_map(|block| block.txs.into_iter());
+        database.fail_writes_of("txid3").await?;
+        assert!(database.store_txs(txs).await.is_err());
+        // The txs before the interruption must not be stored either
+        assert_eq!(
+            database.get_address_txids("address1").await?,
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            database
+                .get_txid_from_vin(&Vin {
+                    txid: "spenttxid".to_owned(),
+                    n: 3
+                })
+                .await?,
+            None
+        );
+        assert_eq!(database.get_delegations("staker1").await?, vec![]);
+        Ok(())
+    }
+
+    pub async fn it_replaces_reorged_spenders<D: Database>(
+        mut database: D,
+    ) -> crate::error::Result<()> {
+        let vin = Vin {
+            txid: "spenttxid".to_owned(),
+            n: 0,
+        };
+        let tx = |txid: &str| Tx {
+            txid: txid.to_owned(),
+            height: None,
+            vin: vec![vin.clone()],
+            addresses: vec![],
+            delegations: vec![],
+            shield_spends: vec![],
+            shield_outputs: vec![],
+        };
+        database.store_tx(&tx("orphantxid")).await?;
+        database.store_tx(&tx("txid1")).await?;
+        assert_eq!(
+            database.get_txid_from_vin(&vin).await?,
+            Some("txid1".to_owned())
+        );
+        Ok(())
+    }
+
+    async fn store_test_blocks<D: Database>(database: &mut D) -> crate::error::Result<()> {
+        let blocks = get_test_blocks();
+        let compact_blocks: Vec<_> = blocks.iter().filter_map(|b| b.to_compact()).collect();
+        let headers: Vec<_> = blocks.iter().filter_map(|b| b.to_header()).collect();
+        database
+            .store_compact_blocks(compact_blocks.into_iter())
+            .await?;
+        database.store_headers(headers.into_iter()).await?;
+        database
+            .store_sapling_trees((1..=3).map(|height| SaplingTree {
+                height,
+                root: "root".to_owned(),
+                tree: "tree".to_owned(),
+            }))
+            .await?;
+        database
+            .store_txs(blocks.into_iter().flat_map(|block| block.txs.into_iter()))
+            .await?;
+        database.update_block_count(3).await
+    }
+
+    pub async fn it_rewinds<D: Database>(mut database: D) -> crate::error::Result<()> {
+        store_test_blocks(&mut database).await?;
+        database
+            .store_raw_tx(&RawTx {
+                txid: "txid2".to_owned(),
+                hex: "hex".to_owned(),
+                block_hash: "hash2".to_owned(),
+                height: 2,
+                time: 1060,
+            })
+            .await?;
+        database.rewind(1).await?;
+        assert_eq!(database.get_last_indexed_block().await?, 1);
+        assert!(database.get_header(1).await?.is_some());
+        assert_eq!(database.get_header(2).await?, None);
+        assert_eq!(database.get_header_by_hash("hash3").await?, None);
+        assert_eq!(
+            database.get_header_at_time(2000).await?.map(|h| h.height),
+            Some(1)
+        );
+        assert_eq!(database.get_compact_blocks(0, 10).await?, vec![]);
+        assert_eq!(
+            database.get_spent_nullifiers(&["nullifier1"]).await?,
+            vec![]
+        );
+        assert_eq!(
+            database.get_sapling_tree(10).await?.map(|tree| tree.height),
+            Some(1)
+        );
+        assert_eq!(database.get_raw_tx("txid2").await?, None);
+        // Only the txs of block 1 are left
+        assert_eq!(database.get_address_txids("address1").await?, vec!["txid1"]);
+        assert_eq!(
+            database.get_address_txids("address4").await?,
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            database
+                .get_txid_from_vin(&Vin {
+                    txid: "spenttxid2".to_owned(),
+                    n: 5,
+                })
+                .await?,
+            None
+        );
+        let delegations = database.get_delegations("staker1").await?;
+        assert_eq!(
+            delegations
+                .iter()
+                .map(|d| d.txid.as_str())
+                .collect::<Vec<_>>(),
+            vec!["txid1"]
+        );
+        // The next sync stores the blocks again
+        store_test_blocks(&mut database).await?;
+        test_address_retrival(&database).await?;
+        assert_eq!(database.get_compact_blocks(0, 10).await?.len(), 1);
+        assert_eq!(database.get_last_indexed_block().await?, 3);
+        Ok(())
+    }
+
+    pub async fn it_wipes<D: Database>(mut database: D) -> crate::error::Result<()> {
+        store_test_blocks(&mut database).await?;
+        database.wipe().await?;
+        assert_eq!(database.get_last_indexed_block().await?, 0);
+        assert_eq!(
+            database.get_address_txids("address1").await?,
+            Vec::<String>::new()
+        );
+        assert_eq!(database.get_delegations("staker1").await?, vec![]);
+        assert_eq!(database.get_header(1).await?, None);
+        assert_eq!(database.get_sapling_tree(10).await?, None);
+        assert_eq!(database.get_compact_blocks(0, 10).await?, vec![]);
+        // The database is still usable
+        store_test_blocks(&mut database).await?;
+        test_address_retrival(&database).await
+    }
+
+    pub async fn it_backs_up_and_restores<D: Database>(
+        mut database: D,
+    ) -> crate::error::Result<()> {
+        let temp_dir = tempdir::TempDir::new("backup-test")?;
+        let path = temp_dir.path().join("backup");
+        store_test_blocks(&mut database).await?;
+        database.backup(path.clone()).await?;
+        database.wipe().await?;
+        // Data that is not in the backup is dropped
+        database
+            .store_tx(&Tx {
+                txid: "othertxid".to_owned(),
+                height: None,
+                addresses: vec!["address1".to_owned()],
+                vin: vec![],
+                delegations: vec![],
+                shield_spends: vec![],
+                shield_outputs: vec![],
+            })
+            .await?;
+        database.update_block_count(10).await?;
+        database.restore(path).await?;
+        test_address_retrival(&database).await?;
+        assert_eq!(database.get_last_indexed_block().await?, 3);
+        assert_eq!(
+            database
+                .get_header_by_hash("hash2")
+                .await?
+                .map(|h| h.height),
+            Some(2)
+        );
+        assert_eq!(database.get_compact_blocks(0, 10).await?.len(), 1);
+        assert_eq!(
+            database.get_sapling_tree(10).await?.map(|tree| tree.height),
+            Some(3)
+        );
+        // The restored database is still usable
+        store_test_blocks(&mut database).await?;
+        test_address_retrival(&database).await
+    }
+}
+
+#[cfg(test)]
+mod mock_db_test {
+    use super::conformance::conformance_tests;
+    use super::test::MockDB;
+
+    async fn new_mock_db() -> crate::error::Result<((), MockDB)> {
+        Ok(((), MockDB::default()))
+    }
+
+    conformance_tests!(new_mock_db);
 }
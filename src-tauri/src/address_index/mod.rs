@@ -3,18 +3,92 @@ pub mod block_file_source;
 pub mod block_source;
 pub mod database;
 pub mod pivx_rpc;
+pub mod sapling_tree;
+pub mod script;
+#[cfg(feature = "sled")]
+pub mod sled_db;
+pub mod snapshot;
 pub mod sql_lite;
 pub mod types;
 
+use crate::address::Address;
+use crate::error::PIVXErrors;
 use block_source::{BlockSource, BlockSourceType};
 use database::Database;
 use futures::StreamExt;
-use types::{Block, Vin};
+use sapling_tree::SaplingTreeState;
+use snapshot::TempFile;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use types::{
+    Block, BlockHeader, CompactBlock, Delegation, IndexMismatch, RawTx, SaplingTree,
+    SnapshotManifest, SpentNullifier, SyncState, SyncStatus, VerifyReport, Vin,
+};
+
+/// A snapshot of the sapling tree is stored every this many blocks
+pub const SAPLING_TREE_CHECKPOINT_INTERVAL: u64 = 1000;
+
+/// Shared by the clones of an `AddressIndex`, so that a sync can be controlled from other tasks
+#[derive(Default)]
+struct SyncControl {
+    /// Held while syncing, so that there is at most one sync at a time
+    running: tokio::sync::Mutex<()>,
+    cancelled: AtomicBool,
+    cancel: tokio::sync::Notify,
+    state: Mutex<SyncState>,
+    /// Sync for `AddressIndex::run_syncs` to start once the running one returns
+    requested: Mutex<Option<SyncState>>,
+    request: tokio::sync::Notify,
+}
+
+impl SyncControl {
+    /**
+     * Cancels the running sync, if any, and the requested one, and waits for it to return
+     */
+    async fn stop(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.cancel.notify_waiters();
+        let running = self.running.lock().await;
+        self.cancelled.store(false, Ordering::SeqCst);
+        *self.requested.lock().unwrap() = None;
+        running
+    }
+
+    /**
+     * Asks `AddressIndex::run_syncs` to sync with `state` once the running sync returns
+     */
+    fn request(&self, state: SyncState) {
+        *self.requested.lock().unwrap() = Some(state);
+        self.request.notify_one();
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /**
+     * Resolves when the sync is cancelled,
+     * so that a sync waiting for new blocks can be stopped too
+     */
+    async fn cancelled(&self) {
+        let mut notified = std::pin::pin!(self.cancel.notified());
+        notified.as_mut().enable();
+        if !self.is_cancelled() {
+            notified.await;
+        }
+    }
+
+    fn set_state(&self, state: SyncState) {
+        *self.state.lock().unwrap() = state;
+    }
+}
 
 #[derive(Clone)]
 pub struct AddressIndex<D: Database> {
     database: D,
     block_source: BlockSourceType,
+    control: Arc<SyncControl>,
 }
 
 impl<D> AddressIndex<D>
@@ -22,43 +96,450 @@ where
     D: Database + Send,
 {
     pub async fn sync(&mut self) -> crate::error::Result<()> {
+        let control = self.control.clone();
+        let _running = control.running.lock().await;
+        self.sync_locked(SyncState::Syncing).await
+    }
+
+    /**
+     * Runs the syncs requested with `request_sync`, `reindex`, `rebuild` and `import_snapshot`
+     * one at a time. It never returns, so it's meant to run in a background task
+     */
+    pub async fn run_syncs(&mut self) {
+        loop {
+            self.control.request.notified().await;
+            if let Err(err) = self.run_requested_sync().await {
+                eprintln!("Warning: sync failed with error {}", err);
+            }
+        }
+    }
+
+    async fn run_requested_sync(&mut self) -> crate::error::Result<()> {
+        let control = self.control.clone();
+        let _running = control.running.lock().await;
+        let state = control.requested.lock().unwrap().take();
+        match state {
+            Some(state) => self.sync_locked(state).await,
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * Starts a sync in `run_syncs` once the running one, if any, returns
+     */
+    pub fn request_sync(&self) {
+        self.control.request(SyncState::Syncing);
+    }
+
+    /**
+     * Stops the running sync, if any. Blocks stored so far stay indexed
+     */
+    pub async fn cancel_sync(&self) {
+        drop(self.control.stop().await);
+    }
+
+    /**
+     * Stops the running sync and starts syncing again from `height` in `run_syncs`.
+     * The blocks up to `height` stay queryable meanwhile
+     */
+    pub async fn reindex(&mut self, height: u64) -> crate::error::Result<()> {
+        let control = self.control.clone();
+        let _running = control.stop().await;
+        self.database.rewind(height).await?;
+        control.set_state(SyncState::Syncing);
+        control.request(SyncState::Syncing);
+        Ok(())
+    }
+
+    /**
+     * Stops the running sync, wipes the index and starts syncing from scratch in `run_syncs`
+     */
+    pub async fn rebuild(&mut self) -> crate::error::Result<()> {
+        let control = self.control.clone();
+        let _running = control.stop().await;
+        self.database.wipe().await?;
+        control.set_state(SyncState::Rebuilding);
+        control.request(SyncState::Rebuilding);
+        Ok(())
+    }
+
+    /**
+     * Writes the index with its tip to a compressed snapshot at `path`.
+     * Blocks stored after reading the tip may be in the backup too, they're rewound on import
+     */
+    pub async fn export_snapshot(&self, path: PathBuf) -> crate::error::Result<SnapshotManifest> {
+        let height = self.database.get_last_indexed_block().await?;
+        let header = self
+            .database
+            .get_header(height)
+            .await?
+            .ok_or(PIVXErrors::InvalidSnapshot)?;
+        let backup = TempFile(snapshot::backup_path(&path));
+        let _ = std::fs::remove_file(&backup.0);
+        self.database.backup(backup.0.clone()).await?;
+        tauri::async_runtime::spawn_blocking(move || -> crate::error::Result<_> {
+            let manifest = SnapshotManifest {
+                height,
+                block_hash: header.hash,
+                sha256: sha256::try_digest(&backup.0)?,
+            };
+            snapshot::write_snapshot(&path, &manifest, &backup.0)?;
+            Ok(manifest)
+        })
+        .await?
+    }
+
+    /**
+     * Replaces the index with the snapshot at `path` and starts syncing forward from its tip
+     * in `run_syncs`. The snapshot is rejected if its tip is not in the chain of the block source,
+     * or with `SnapshotAheadOfNode` if the block source hasn't reached it yet
+     */
+    pub async fn import_snapshot(&mut self, path: PathBuf) -> crate::error::Result<()> {
+        let backup = TempFile(snapshot::backup_path(&path));
+        let backup_path = backup.0.clone();
+        let manifest = tauri::async_runtime::spawn_blocking(move || {
+            snapshot::read_snapshot(&path, &backup_path)
+        })
+        .await??;
+        let block_source = self.indexed_block_source()?;
+        // The snapshot may be on a fork pivxd hasn't reached yet, so its tip can't be judged
+        if manifest.height > block_source.get_block_count().await? {
+            return Err(PIVXErrors::SnapshotAheadOfNode);
+        }
+        if block_source.get_block_hash(manifest.height).await? != manifest.block_hash {
+            return Err(PIVXErrors::InvalidSnapshot);
+        }
+        let control = self.control.clone();
+        let _running = control.stop().await;
+        self.database.restore(backup.0.clone()).await?;
+        drop(backup);
+        self.database.rewind(manifest.height).await?;
+        control.set_state(SyncState::Syncing);
+        control.request(SyncState::Syncing);
+        Ok(())
+    }
+
+    pub async fn get_sync_status(&self) -> crate::error::Result<SyncStatus> {
+        let state = *self.control.state.lock().unwrap();
+        Ok(SyncStatus {
+            state,
+            block_count: self.database.get_last_indexed_block().await?,
+        })
+    }
+
+    /**
+     * Must be called while holding `self.control.running`
+     */
+    async fn sync_locked(&mut self, state: SyncState) -> crate::error::Result<()> {
+        self.control.set_state(state);
+        let result = self.sync_blocks().await;
+        if self.control.is_cancelled() {
+            println!("Sync cancelled");
+        }
+        self.control.set_state(match result {
+            _ if self.control.is_cancelled() => SyncState::Cancelled,
+            Ok(()) => SyncState::Idle,
+            Err(_) => SyncState::Failed,
+        });
+        result
+    }
+
+    async fn sync_blocks(&mut self) -> crate::error::Result<()> {
         println!("Starting sync");
         match &self.block_source {
             BlockSourceType::Regular(block_source) => {
-                let mut stream = block_source.get_blocks()?.chunks(500_000);
+                let mut stream = std::pin::pin!(block_source
+                    .get_blocks()?
+                    .take_until(self.control.cancelled())
+                    .chunks(500_000));
                 while let Some(blocks) = stream.next().await {
-                    Self::store_blocks(&mut self.database, blocks.into_iter()).await?;
+                    // Block files are not sorted, so the sapling tree can't be built
+                    Self::store_blocks(&mut self.database, blocks, None).await?;
                 }
             }
             BlockSourceType::Indexed(block_source) => {
-                let start = self.database.get_last_indexed_block().await?;
-                let mut stream = block_source.get_blocks_indexed(start)?.chunks(10);
-                while let Some(blocks) = stream.next().await {
-                    let block_count = blocks.last().map(|(_, i)| *i);
-                    Self::store_blocks(
-                        &mut self.database,
-                        blocks.into_iter().map(|(block, _)| block),
-                    )
-                    .await?;
-                    if let Some(block_count) = block_count {
-                        self.database.update_block_count(block_count).await?;
+                let mut start = self.database.get_last_indexed_block().await?;
+                let tip = block_source.get_block_count().await?;
+                // Restarted from the fork point after each reorg
+                loop {
+                    let mut sapling_tree = self.load_sapling_tree(start).await?;
+                    // The stream waits for new blocks at the tip, so don't wait for full chunks
+                    let mut stream = std::pin::pin!(block_source
+                        .get_blocks_indexed(start)?
+                        .take_until(self.control.cancelled())
+                        .ready_chunks(10));
+                    if start >= tip {
+                        self.control.set_state(SyncState::Idle);
+                    }
+                    let mut fork = None;
+                    while let Some(mut blocks) = stream.next().await {
+                        let linked = Self::count_linked(&self.database, &blocks).await?;
+                        let reorged = linked < blocks.len();
+                        blocks.truncate(linked);
+                        let block_count = blocks.last().map(|(_, i)| *i);
+                        Self::store_blocks(
+                            &mut self.database,
+                            blocks.into_iter().map(|(block, _)| block).collect(),
+                            Some(&mut sapling_tree),
+                        )
+                        .await?;
+                        if let Some(block_count) = block_count {
+                            self.database.update_block_count(block_count).await?;
+                            if block_count >= tip {
+                                self.control.set_state(SyncState::Idle);
+                            }
+                        }
+                        if reorged {
+                            let height = self.database.get_last_indexed_block().await?;
+                            fork =
+                                Some(Self::find_fork(&self.database, block_source, height).await?);
+                            break;
+                        }
                     }
+                    let Some(fork) = fork else {
+                        break;
+                    };
+                    println!("Chain reorganized, rewinding the index to block {}", fork);
+                    // Also drops the sapling checkpoints, compact blocks and nullifiers above it
+                    self.database.rewind(fork).await?;
+                    start = fork;
                 }
             }
         }
         Ok(())
     }
 
+    /**
+     * Returns how many of `blocks` extend the indexed chain, each one building on the previous.
+     * Fewer than all of them means the chain of the block source reorganized
+     */
+    async fn count_linked(database: &D, blocks: &[(Block, u64)]) -> crate::error::Result<usize> {
+        let Some((_, first_height)) = blocks.first() else {
+            return Ok(0);
+        };
+        // Without a header below the blocks there is nothing to check them against
+        let mut prev_hash = match first_height.checked_sub(1) {
+            Some(height) => database.get_header(height).await?.map(|header| header.hash),
+            None => None,
+        };
+        Ok(blocks
+            .iter()
+            .take_while(|(block, _)| {
+                let linked = match &prev_hash {
+                    Some(hash) => *hash == block.prev_hash,
+                    None => true,
+                };
+                prev_hash = Some(block.hash.clone());
+                linked
+            })
+            .count())
+    }
+
+    /**
+     * Returns the highest height up to `height` whose indexed header is still in the chain
+     * of the block source, which is where the index forked off it
+     */
+    async fn find_fork(
+        database: &D,
+        block_source: &block_source::Ibs,
+        mut height: u64,
+    ) -> crate::error::Result<u64> {
+        while height > 0 {
+            match database.get_header(height).await? {
+                Some(header) if header.hash != block_source.get_block_hash(height).await? => {
+                    height -= 1
+                }
+                _ => break,
+            }
+        }
+        Ok(height)
+    }
+
     async fn store_blocks(
         database: &mut D,
-        blocks: impl Iterator<Item = Block>,
+        blocks: Vec<Block>,
+        mut sapling_tree: Option<&mut SaplingTreeState>,
     ) -> crate::error::Result<()> {
+        let mut compact_blocks = vec![];
+        let mut checkpoints = vec![];
+        let headers: Vec<_> = blocks.iter().filter_map(Block::to_header).collect();
+        for block in &blocks {
+            let compact_block = block.to_compact();
+            if let (Some(sapling_tree), Some(height)) = (sapling_tree.as_deref_mut(), block.height)
+            {
+                sapling_tree.append_block(height, compact_block.as_ref())?;
+                if height % SAPLING_TREE_CHECKPOINT_INTERVAL == 0 && sapling_tree.height == height {
+                    checkpoints.push(sapling_tree.snapshot());
+                }
+            }
+            compact_blocks.extend(compact_block);
+        }
+        database
+            .store_compact_blocks(compact_blocks.into_iter())
+            .await?;
+        database
+            .store_sapling_trees(checkpoints.into_iter())
+            .await?;
+        database.store_headers(headers.into_iter()).await?;
         database
-            .store_txs(blocks.flat_map(|block| block.txs.into_iter()))
+            .store_txs(blocks.into_iter().flat_map(|block| block.txs.into_iter()))
             .await?;
         Ok(())
     }
 
+    /**
+     * Rebuilds the sapling tree at `height`
+     * from the last checkpoint and the compact blocks stored after it
+     */
+    async fn load_sapling_tree(&self, height: u64) -> crate::error::Result<SaplingTreeState> {
+        let mut sapling_tree = match self.database.get_sapling_tree(height).await? {
+            Some(snapshot) => SaplingTreeState::from_snapshot(&snapshot)?,
+            None => SaplingTreeState::default(),
+        };
+        let compact_blocks = self
+            .database
+            .get_compact_blocks(sapling_tree.height + 1, height)
+            .await?;
+        for block in &compact_blocks {
+            sapling_tree.append_block(block.height, Some(block))?;
+        }
+        sapling_tree.height = sapling_tree.height.max(height);
+        Ok(sapling_tree)
+    }
+
+    fn indexed_block_source(&self) -> crate::error::Result<&block_source::Ibs> {
+        match &self.block_source {
+            BlockSourceType::Indexed(block_source) => Ok(block_source),
+            BlockSourceType::Regular(_) => Err(PIVXErrors::NoIndexedBlockSource),
+        }
+    }
+
+    async fn get_block(&self, height: u64) -> crate::error::Result<Block> {
+        let mut stream = self
+            .indexed_block_source()?
+            .get_blocks_indexed(height.saturating_sub(1))?;
+        match stream.next().await {
+            Some((block, block_height)) if block_height == height => Ok(block),
+            _ => Err(PIVXErrors::InvalidBlock),
+        }
+    }
+
+    /**
+     * Compares the indexed header, addresses and vins of the block at `height`
+     * with the block from the block source
+     */
+    async fn verify_block(&self, height: u64) -> crate::error::Result<Vec<IndexMismatch>> {
+        let block = self.get_block(height).await?;
+        let mut mismatches = vec![];
+        match self.database.get_header(height).await? {
+            None => mismatches.push(IndexMismatch::MissingHeader { height }),
+            Some(header) if header.hash != block.hash => {
+                mismatches.push(IndexMismatch::WrongHeader {
+                    height,
+                    expected_hash: block.hash.clone(),
+                    indexed_hash: header.hash,
+                })
+            }
+            Some(_) => {}
+        }
+        for tx in &block.txs {
+            let stakers = tx.delegations.iter().map(|d| &d.staker);
+            for address in tx.addresses.iter().chain(stakers) {
+                if !self
+                    .database
+                    .get_address_txids(address)
+                    .await?
+                    .contains(&tx.txid)
+                {
+                    mismatches.push(IndexMismatch::MissingAddressTx {
+                        height,
+                        address: address.clone(),
+                        txid: tx.txid.clone(),
+                    });
+                }
+            }
+            for vin in &tx.vin {
+                let indexed_txid = self.database.get_txid_from_vin(vin).await?;
+                if indexed_txid.as_ref() != Some(&tx.txid) {
+                    mismatches.push(IndexMismatch::WrongSpender {
+                        height,
+                        vin: vin.clone(),
+                        expected_txid: tx.txid.clone(),
+                        indexed_txid,
+                    });
+                }
+            }
+        }
+        Ok(mismatches)
+    }
+
+    /**
+     * Checks the indexed blocks in `from..=to` against the block source,
+     * or only `sample_size` of them evenly spread over the range.
+     * If `repair` is set, the range between the first and last mismatch is indexed again,
+     * with the running sync stopped meanwhile and started again after.
+     * Txs that were indexed for blocks no longer in the chain are not detected
+     */
+    pub async fn verify(
+        &mut self,
+        from: u64,
+        to: u64,
+        sample_size: Option<u64>,
+        repair: bool,
+    ) -> crate::error::Result<VerifyReport> {
+        self.indexed_block_source()?;
+        // The block source can't return the genesis block
+        let from = from.max(1);
+        let to = to.min(self.database.get_last_indexed_block().await?);
+        let range_size = (to + 1).saturating_sub(from);
+        let step = match sample_size {
+            Some(sample_size) if sample_size > 0 && sample_size < range_size => {
+                range_size / sample_size
+            }
+            _ => 1,
+        };
+        let mut checked_blocks = 0;
+        let mut mismatches = vec![];
+        for height in (from..=to).step_by(step as usize) {
+            if sample_size.is_some_and(|sample_size| checked_blocks >= sample_size) {
+                break;
+            }
+            mismatches.extend(self.verify_block(height).await?);
+            checked_blocks += 1;
+        }
+
+        let mut repaired = None;
+        let heights = mismatches.iter().map(IndexMismatch::height);
+        if let (true, Some(first), Some(last)) = (repair, heights.clone().min(), heights.max()) {
+            let control = self.control.clone();
+            let was_syncing = control.running.try_lock().is_err();
+            let _running = control.stop().await;
+            self.reindex_range(first, last).await?;
+            if was_syncing {
+                control.set_state(SyncState::Syncing);
+                control.request(SyncState::Syncing);
+            }
+            repaired = Some((first, last));
+        }
+        Ok(VerifyReport {
+            checked_blocks,
+            mismatches,
+            repaired,
+        })
+    }
+
+    async fn reindex_range(&mut self, from: u64, to: u64) -> crate::error::Result<()> {
+        let mut sapling_tree = self.load_sapling_tree(from - 1).await?;
+        let blocks: Vec<_> = self
+            .indexed_block_source()?
+            .get_blocks_indexed(from - 1)?
+            .take((to - from + 1) as usize)
+            .map(|(block, _)| block)
+            .collect()
+            .await;
+        Self::store_blocks(&mut self.database, blocks, Some(&mut sapling_tree)).await
+    }
+
     pub fn new<B>(database: D, block_source: B) -> Self
     where
         B: BlockSource + 'static + Send + Sync,
@@ -66,21 +547,98 @@ where
         Self {
             database,
             block_source: block_source.instantiate(),
+            control: Arc::default(),
         }
     }
+    /**
+     * Returns the txids of `address`.
+     * Exchange addresses and their D-address share the same history
+     */
     pub async fn get_address_txids(&self, address: &str) -> crate::error::Result<Vec<String>> {
-        self.database.get_address_txids(address).await
+        let mut txids = self.database.get_address_txids(address).await?;
+        let pair = Address::decode(address).and_then(|address| address.get_exchange_pair());
+        if let Some(pair) = pair {
+            for txid in self.database.get_address_txids(&pair.encode()).await? {
+                if !txids.contains(&txid) {
+                    txids.push(txid);
+                }
+            }
+        }
+        Ok(txids)
     }
 
     pub async fn get_txid_from_vin(&self, vin: &Vin) -> crate::error::Result<Option<String>> {
         self.database.get_txid_from_vin(vin).await
     }
+
+    pub async fn get_delegations(&self, staker: &str) -> crate::error::Result<Vec<Delegation>> {
+        self.database.get_delegations(staker).await
+    }
+
+    pub async fn get_delegated_balance(&self, staker: &str) -> crate::error::Result<u64> {
+        self.database.get_delegated_balance(staker).await
+    }
+
+    pub async fn get_compact_blocks(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> crate::error::Result<Vec<CompactBlock>> {
+        self.database.get_compact_blocks(from, to).await
+    }
+
+    pub async fn get_header(&self, height: u64) -> crate::error::Result<Option<BlockHeader>> {
+        self.database.get_header(height).await
+    }
+
+    pub async fn get_header_by_hash(
+        &self,
+        hash: &str,
+    ) -> crate::error::Result<Option<BlockHeader>> {
+        self.database.get_header_by_hash(hash).await
+    }
+
+    pub async fn get_header_at_time(&self, time: u64) -> crate::error::Result<Option<BlockHeader>> {
+        self.database.get_header_at_time(time).await
+    }
+
+    /**
+     * Returns the cached raw tx, unless the block it was mined in has been reorged out
+     */
+    pub async fn get_raw_tx(&self, txid: &str) -> crate::error::Result<Option<RawTx>> {
+        let Some(raw_tx) = self.database.get_raw_tx(txid).await? else {
+            return Ok(None);
+        };
+        match self.database.get_header(raw_tx.height).await? {
+            Some(header) if header.hash != raw_tx.block_hash => Ok(None),
+            _ => Ok(Some(raw_tx)),
+        }
+    }
+
+    pub async fn store_raw_tx(&mut self, raw_tx: &RawTx) -> crate::error::Result<()> {
+        self.database.store_raw_tx(raw_tx).await
+    }
+
+    pub async fn get_sapling_tree(&self, height: u64) -> crate::error::Result<Option<SaplingTree>> {
+        self.database.get_sapling_tree(height).await
+    }
+
+    pub async fn get_spent_nullifiers(
+        &self,
+        nullifiers: &[&str],
+    ) -> crate::error::Result<Vec<SpentNullifier>> {
+        self.database.get_spent_nullifiers(nullifiers).await
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::block_source::test::MockBlockSource;
+    use super::block_source::test::{
+        MockBlockSource, MockChain, MockIndexedBlockSource, MockStalledBlockSource,
+    };
     use super::database::test::MockDB;
+    use super::types::test::get_test_blocks;
+    use super::types::{ShieldOutput, Tx};
     use super::*;
 
     #[tokio::test]
@@ -109,6 +667,430 @@ mod test {
             address_index.get_address_txids("address6").await?,
             Vec::<String>::new()
         );
+        assert_eq!(address_index.get_delegated_balance("staker1").await?, 250);
+        let header = address_index.get_header(2).await?.unwrap();
+        assert_eq!((header.hash.as_str(), header.time), ("hash2", 1060));
+        assert_eq!(
+            address_index.get_header_by_hash("hash2").await?,
+            Some(header)
+        );
+        assert_eq!(
+            address_index
+                .get_header_at_time(1119)
+                .await?
+                .map(|h| h.height),
+            Some(2)
+        );
+        // Only block 2 has shield transactions
+        let compact_blocks = address_index.get_compact_blocks(0, 10).await?;
+        assert_eq!(compact_blocks.len(), 1);
+        assert_eq!(compact_blocks[0].height, 2);
+        assert_eq!(compact_blocks[0].txs[0].txid, "txid2");
+        assert_eq!(address_index.get_compact_blocks(3, 10).await?, vec![]);
+        let spent = address_index
+            .get_spent_nullifiers(&["nullifier1", "nullifier2"])
+            .await?;
+        assert_eq!(spent.len(), 1);
+        assert_eq!((spent[0].txid.as_str(), spent[0].height), ("txid2", 2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn merges_exchange_address_history() -> crate::error::Result<()> {
+        let mut mock_db = MockDB::default();
+        let tx = |txid: &str, address: &str| Tx {
+            txid: txid.to_owned(),
+            height: None,
+            vin: vec![],
+            addresses: vec![address.to_owned()],
+            delegations: vec![],
+            shield_spends: vec![],
+            shield_outputs: vec![],
+        };
+        mock_db
+            .store_tx(&tx("txid1", "DDU6BCfxp2eGdQ5AuoyL4QQo6D4abms5qg"))
+            .await?;
+        mock_db
+            .store_tx(&tx("txid2", "EXMKLh2d1jUdovx4xfF7Tye8uTSHvz3QUjBX"))
+            .await?;
+        let address_index = AddressIndex::new(mock_db, MockBlockSource);
+        assert_eq!(
+            address_index
+                .get_address_txids("DDU6BCfxp2eGdQ5AuoyL4QQo6D4abms5qg")
+                .await?,
+            vec!["txid1", "txid2"]
+        );
+        assert_eq!(
+            address_index
+                .get_address_txids("EXMKLh2d1jUdovx4xfF7Tye8uTSHvz3QUjBX")
+                .await?,
+            vec!["txid2", "txid1"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn builds_sapling_tree() -> crate::error::Result<()> {
+        // Blocks are at heights 999, 1000 and 1001, the shield tx is in block 1000
+        let block_source = MockIndexedBlockSource { first_height: 999 };
+        let mut address_index = AddressIndex::new(MockDB::default(), block_source);
+        address_index.sync().await?;
+
+        let mut tree = sapling_tree::CommitmentTree::default();
+        tree.append([1; 32]);
+        let expected = SaplingTreeState { height: 1000, tree };
+        assert_eq!(address_index.get_sapling_tree(999).await?, None);
+        assert_eq!(
+            address_index.get_sapling_tree(1001).await?,
+            Some(expected.snapshot())
+        );
+        // The tree is rebuilt from the checkpoint and the following blocks
+        let loaded = address_index.load_sapling_tree(1001).await?;
+        assert_eq!(loaded.height, 1001);
+        assert_eq!(loaded.tree, expected.tree);
+        Ok(())
+    }
+
+    /**
+     * Returns the test blocks with the ones after the first replaced by a longer fork,
+     * where the second block has `txs`
+     */
+    fn forked_blocks(txs: Vec<Tx>) -> Vec<Block> {
+        let mut blocks = get_test_blocks();
+        blocks.push(get_test_blocks().remove(2));
+        let mut prev_hash = blocks[0].hash.clone();
+        for (i, block) in blocks.iter_mut().enumerate().skip(1) {
+            block.hash = format!("forkhash{}", i + 1);
+            block.prev_hash = prev_hash;
+            block.txs = vec![];
+            prev_hash = block.hash.clone();
+        }
+        blocks[1].txs = txs;
+        blocks
+    }
+
+    #[tokio::test]
+    async fn rewinds_reorged_blocks() -> crate::error::Result<()> {
+        let mut address_index =
+            AddressIndex::new(MockDB::default(), MockChain::new(1, get_test_blocks()));
+        address_index.sync().await?;
+        let spent = Vin {
+            txid: "spenttxid".to_owned(),
+            n: 1,
+        };
+        let fork = forked_blocks(vec![Tx {
+            txid: "txid4".to_owned(),
+            height: None,
+            addresses: vec!["address1".to_owned()],
+            vin: vec![spent.clone()],
+            delegations: vec![],
+            shield_spends: vec![],
+            shield_outputs: vec![],
+        }]);
+        let mut address_index =
+            AddressIndex::new(address_index.database.clone(), MockChain::new(1, fork));
+        address_index.sync().await?;
+
+        assert_eq!(address_index.get_sync_status().await?.block_count, 4);
+        assert_eq!(
+            address_index.get_address_txids("address1").await?,
+            vec!["txid1", "txid4"]
+        );
+        assert_eq!(
+            address_index.get_address_txids("address4").await?,
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            address_index.get_txid_from_vin(&spent).await?,
+            Some("txid4".to_owned())
+        );
+        // The delegation of block 3 is gone, the one of block 1 is no longer spent by txid2
+        assert_eq!(address_index.get_delegated_balance("staker1").await?, 100);
+        assert_eq!(address_index.get_header_by_hash("hash3").await?, None);
+        let header = address_index.get_header(2).await?.unwrap();
+        assert_eq!(
+            (header.hash.as_str(), header.prev_hash.as_str()),
+            ("forkhash2", "hash1")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rewinds_the_sapling_tree_on_reorgs() -> crate::error::Result<()> {
+        // Blocks are at heights 999, 1000 and 1001, the shield tx is in block 1000
+        let mut address_index =
+            AddressIndex::new(MockDB::default(), MockChain::new(999, get_test_blocks()));
+        address_index.sync().await?;
+        // The fork replaces it with a shield tx with another output and no spends
+        let fork = forked_blocks(vec![Tx {
+            txid: "txid4".to_owned(),
+            height: None,
+            addresses: vec![],
+            vin: vec![],
+            delegations: vec![],
+            shield_spends: vec![],
+            shield_outputs: vec![ShieldOutput {
+                cmu: "02".repeat(32),
+                epk: "epk4".to_owned(),
+                ciphertext: "ciphertext4".to_owned(),
+            }],
+        }]);
+        let mut address_index =
+            AddressIndex::new(address_index.database.clone(), MockChain::new(999, fork));
+        address_index.sync().await?;
+
+        let mut tree = sapling_tree::CommitmentTree::default();
+        tree.append([2; 32]);
+        let expected = SaplingTreeState { height: 1000, tree };
+        assert_eq!(
+            address_index.get_sapling_tree(1002).await?,
+            Some(expected.snapshot())
+        );
+        assert_eq!(
+            address_index.load_sapling_tree(1002).await?.tree,
+            expected.tree
+        );
+        let compact_blocks = address_index.get_compact_blocks(0, 2000).await?;
+        assert_eq!(compact_blocks.len(), 1);
+        assert_eq!(compact_blocks[0].txs[0].txid, "txid4");
+        assert_eq!(
+            address_index.get_spent_nullifiers(&["nullifier1"]).await?,
+            vec![]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn caches_raw_txs() -> crate::error::Result<()> {
+        let mut address_index = AddressIndex::new(MockDB::default(), MockBlockSource);
+        address_index.sync().await?;
+        let raw_tx = |block_hash: &str| RawTx {
+            txid: "txid2".to_owned(),
+            hex: "hex".to_owned(),
+            block_hash: block_hash.to_owned(),
+            height: 2,
+            time: 1060,
+        };
+        address_index.store_raw_tx(&raw_tx("hash2")).await?;
+        assert_eq!(
+            address_index.get_raw_tx("txid2").await?,
+            Some(raw_tx("hash2"))
+        );
+        // Block 2 has a different hash, so the cached tx is stale
+        address_index.store_raw_tx(&raw_tx("hash2b")).await?;
+        assert_eq!(address_index.get_raw_tx("txid2").await?, None);
+        assert_eq!(address_index.get_raw_tx("txid1").await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verifies_and_repairs_the_index() -> crate::error::Result<()> {
+        let block_source = MockIndexedBlockSource { first_height: 1 };
+        let mut address_index = AddressIndex::new(MockDB::default(), block_source);
+        address_index.sync().await?;
+        // Blocks past the last indexed one are not checked
+        let report = address_index.verify(0, 100, None, false).await?;
+        assert_eq!(report.checked_blocks, 3);
+        assert_eq!(report.mismatches, vec![]);
+
+        // Block 2 was indexed from a fork
+        let mut header = address_index.get_header(2).await?.unwrap();
+        header.hash = "hash2b".to_owned();
+        let database = &mut address_index.database;
+        database.store_headers(std::iter::once(header)).await?;
+        let vin = Vin {
+            txid: "txid1".to_owned(),
+            n: 1,
+        };
+        database
+            .store_tx(&Tx {
+                txid: "orphantxid".to_owned(),
+                height: Some(2),
+                vin: vec![vin.clone()],
+                addresses: vec![],
+                delegations: vec![],
+                shield_spends: vec![],
+                shield_outputs: vec![],
+            })
+            .await?;
+        let report = address_index.verify(1, 3, None, true).await?;
+        assert_eq!(
+            report.mismatches,
+            vec![
+                IndexMismatch::WrongHeader {
+                    height: 2,
+                    expected_hash: "hash2".to_owned(),
+                    indexed_hash: "hash2b".to_owned(),
+                },
+                IndexMismatch::WrongSpender {
+                    height: 2,
+                    vin,
+                    expected_txid: "txid2".to_owned(),
+                    indexed_txid: Some("orphantxid".to_owned()),
+                },
+            ]
+        );
+        assert_eq!(report.repaired, Some((2, 2)));
+        // No sync was running, so none is started
+        assert_eq!(*address_index.control.requested.lock().unwrap(), None);
+        let report = address_index.verify(1, 3, None, false).await?;
+        assert_eq!(report.mismatches, vec![]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verifies_a_sample() -> crate::error::Result<()> {
+        let block_source = MockIndexedBlockSource { first_height: 1 };
+        let mut address_index = AddressIndex::new(MockDB::default(), block_source);
+        // The blocks are counted as indexed, but none was stored
+        address_index.database.update_block_count(3).await?;
+        let report = address_index.verify(1, 3, Some(2), false).await?;
+        assert_eq!(report.checked_blocks, 2);
+        assert!(report
+            .mismatches
+            .contains(&IndexMismatch::MissingHeader { height: 1 }));
+        assert!(report.mismatches.iter().all(|m| m.height() <= 2));
+
+        let mut address_index = AddressIndex::new(MockDB::default(), MockBlockSource);
+        assert!(matches!(
+            address_index.verify(1, 3, None, false).await,
+            Err(PIVXErrors::NoIndexedBlockSource)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reindexes_and_rebuilds() -> crate::error::Result<()> {
+        let block_source = MockIndexedBlockSource { first_height: 1 };
+        let mut address_index = AddressIndex::new(MockDB::default(), block_source);
+        address_index.sync().await?;
+        let synced = SyncStatus {
+            state: SyncState::Idle,
+            block_count: 3,
+        };
+        assert_eq!(address_index.get_sync_status().await?, synced);
+
+        // The sync is only requested, `run_syncs` starts it
+        address_index.reindex(1).await?;
+        assert_eq!(
+            address_index.get_sync_status().await?,
+            SyncStatus {
+                state: SyncState::Syncing,
+                block_count: 1,
+            }
+        );
+        address_index.run_requested_sync().await?;
+        assert_eq!(address_index.get_sync_status().await?, synced);
+        assert_eq!(address_index.get_header(3).await?.unwrap().hash, "hash3");
+
+        address_index.rebuild().await?;
+        assert_eq!(
+            address_index.get_sync_status().await?.state,
+            SyncState::Rebuilding
+        );
+        address_index.run_requested_sync().await?;
+        assert_eq!(address_index.get_sync_status().await?, synced);
+        assert_eq!(
+            address_index.get_address_txids("address1").await?,
+            vec!["txid1", "txid2", "txid3"]
+        );
+
+        // Cancelling drops the requested sync
+        address_index.reindex(2).await?;
+        address_index.cancel_sync().await;
+        address_index.run_requested_sync().await?;
+        assert_eq!(address_index.get_sync_status().await?.block_count, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exports_and_imports_snapshots() -> crate::error::Result<()> {
+        let temp_dir = tempdir::TempDir::new("snapshot-test")?;
+        let path = temp_dir.path().join("index.snapshot");
+        let block_source = || MockIndexedBlockSource { first_height: 1 };
+        let mut address_index = AddressIndex::new(MockDB::default(), block_source());
+        address_index.sync().await?;
+        let manifest = address_index.export_snapshot(path.clone()).await?;
+        assert_eq!(
+            (manifest.height, manifest.block_hash.as_str()),
+            (3, "hash3")
+        );
+        assert!(!snapshot::backup_path(&path).exists());
+
+        let mut imported = AddressIndex::new(MockDB::default(), block_source());
+        imported.import_snapshot(path.clone()).await?;
+        imported.run_requested_sync().await?;
+        assert_eq!(
+            imported.get_sync_status().await?,
+            SyncStatus {
+                state: SyncState::Idle,
+                block_count: 3,
+            }
+        );
+        assert_eq!(
+            imported.get_address_txids("address1").await?,
+            vec!["txid1", "txid2", "txid3"]
+        );
+        assert!(!snapshot::backup_path(&path).exists());
+
+        // The tip of the snapshot is not in the chain of the block source
+        let mut forked = AddressIndex::new(
+            MockDB::default(),
+            MockIndexedBlockSource { first_height: 2 },
+        );
+        assert!(matches!(
+            forked.import_snapshot(path.clone()).await,
+            Err(PIVXErrors::InvalidSnapshot)
+        ));
+        assert_eq!(forked.get_sync_status().await?.block_count, 0);
+
+        // The block source is behind the tip of the snapshot
+        let mut behind = AddressIndex::new(
+            MockDB::default(),
+            MockIndexedBlockSource { first_height: 0 },
+        );
+        assert!(matches!(
+            behind.import_snapshot(path.clone()).await,
+            Err(PIVXErrors::SnapshotAheadOfNode)
+        ));
+        assert_eq!(behind.get_sync_status().await?.block_count, 0);
+
+        // The backup doesn't match the checksum
+        let backup = temp_dir.path().join("backup");
+        std::fs::write(&backup, b"tampered")?;
+        snapshot::write_snapshot(&path, &manifest, &backup)?;
+        assert!(matches!(
+            imported.import_snapshot(path).await,
+            Err(PIVXErrors::InvalidSnapshot)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancels_sync() -> crate::error::Result<()> {
+        let mut address_index = AddressIndex::new(MockDB::default(), MockStalledBlockSource);
+        let control = address_index.control.clone();
+        // The sync waits for new blocks forever, until it is cancelled
+        let stop_at_tip = async {
+            // Reaching the tip makes the sync idle
+            while *control.state.lock().unwrap() != SyncState::Idle {
+                tokio::task::yield_now().await;
+            }
+            drop(control.stop().await);
+        };
+        let (result, _) = futures::join!(address_index.sync(), stop_at_tip);
+        result?;
+        assert_eq!(
+            address_index.get_sync_status().await?,
+            SyncStatus {
+                state: SyncState::Cancelled,
+                block_count: 3,
+            }
+        );
+        assert_eq!(
+            address_index.get_address_txids("address1").await?,
+            vec!["txid1", "txid2", "txid3"]
+        );
         Ok(())
     }
 }
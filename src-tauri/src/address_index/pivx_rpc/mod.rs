@@ -1,10 +1,11 @@
 pub mod json_rpc;
 mod test;
 
-use crate::binary::Binary;
 use crate::error::PIVXErrors;
 
-use super::block_source::{BlockSource, BlockSourceType, IndexedBlockSource, PinnedStream};
+use super::block_source::{
+    BlockSource, BlockSourceType, IndexedBlockSource, PinnedFuture, PinnedStream,
+};
 use super::types::Block;
 use base64::prelude::*;
 use futures::stream::Stream;
@@ -14,15 +15,21 @@ use jsonrpsee::core::traits::ToRpcParams;
 use jsonrpsee::rpc_params;
 use reqwest::header::{HeaderMap, HeaderValue};
 use serde::de::DeserializeOwned;
+use serde_json::Value;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// pivxd answers `getblockhash` with this code for heights past the tip
+const RPC_INVALID_PARAMETER: i32 = -8;
+/// Time between two checks for a new block once the stream reached the tip
+const NEW_BLOCK_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Clone)]
 pub struct PIVXRpc {
     client: HttpClient,
-    _pivx: Arc<Binary>,
 }
 
 type BlockStreamFuture = Pin<Box<dyn Future<Output = Option<(Block, u64)>> + Send>>;
@@ -34,19 +41,28 @@ struct BlockStream {
 }
 
 impl BlockStream {
+    async fn fetch_block(
+        client: &HttpClient,
+        height: u64,
+    ) -> Result<Block, json_rpc::Error<Option<Value>>> {
+        let hash: String = client.request("getblockhash", rpc_params![height]).await?;
+        client.request("getblock", rpc_params![hash, 2]).await
+    }
+
+    /**
+     * Fetches the block at `current_block`, waiting for it if it's past the tip.
+     * Other errors are retried too, so that the sync survives pivxd restarts
+     */
     async fn get_next_block(client: HttpClient, current_block: u64) -> Option<(Block, u64)> {
         println!("current block: {}", current_block);
-        let hash: String = client
-            .request::<_, (), _>("getblockhash", rpc_params![current_block])
-            .await
-            .unwrap();
-        let block: Result<Block, _> = client
-            .request::<_, (), _>("getblock", rpc_params![hash, 2])
-            .await;
-        if let Err(ref err) = &block {
-            eprintln!("{}", err);
+        loop {
+            match Self::fetch_block(&client, current_block).await {
+                Ok(block) => return Some((block, current_block)),
+                Err(json_rpc::Error::JSONRpc(err)) if err.code == RPC_INVALID_PARAMETER => {}
+                Err(err) => eprintln!("Warning: failed to fetch block {}: {}", current_block, err),
+            }
+            tokio::time::sleep(NEW_BLOCK_POLL_INTERVAL).await;
         }
-        block.ok().map(|b| (b, current_block))
     }
 
     pub fn new(client: HttpClient) -> Self {
@@ -91,19 +107,24 @@ impl Stream for BlockStream {
     }
 }
 
+/**
+ * Returns a client for the pivxd RPC server at `url`, authenticated with our credentials
+ */
+pub fn new_client(url: &str) -> crate::error::Result<HttpClient> {
+    let mut headers = HeaderMap::new();
+    let credentials = format!("{}:{}", crate::RPC_USERNAME, crate::RPC_PASSWORD);
+    headers.insert(
+        "Authorization",
+        // TODO: remove unwrap
+        HeaderValue::from_str(&format!("Basic {}", BASE64_STANDARD.encode(credentials))).unwrap(),
+    );
+    HttpClient::builder().set_headers(headers).build(url)
+}
+
 impl PIVXRpc {
-    pub async fn new(url: &str, pivx: Binary) -> crate::error::Result<Self> {
-        let mut headers = HeaderMap::new();
-        let credentials = format!("{}:{}", crate::RPC_USERNAME, crate::RPC_PASSWORD);
-        headers.insert(
-            "Authorization",
-            // TODO: remove unwrap
-            HeaderValue::from_str(&format!("Basic {}", BASE64_STANDARD.encode(credentials)))
-                .unwrap(),
-        );
+    pub async fn new(url: &str) -> crate::error::Result<Self> {
         Ok(PIVXRpc {
-            client: HttpClient::builder().set_headers(headers).build(url)?,
-            _pivx: Arc::new(pivx),
+            client: new_client(url)?,
         })
     }
 
@@ -140,6 +161,14 @@ impl IndexedBlockSource for PIVXRpc {
         Ok(Box::pin(block_stream))
     }
 
+    fn get_block_count(&self) -> PinnedFuture<'_, crate::error::Result<u64>> {
+        Box::pin(self.call("getblockcount", rpc_params![]))
+    }
+
+    fn get_block_hash(&self, height: u64) -> PinnedFuture<'_, crate::error::Result<String>> {
+        Box::pin(self.call("getblockhash", rpc_params![height]))
+    }
+
     fn as_block_source(&self) -> &(dyn BlockSource + Send + Sync + 'static) {
         self
     }
@@ -68,4 +68,39 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn waits_for_blocks_past_the_tip() -> Result<(), Box<dyn std::error::Error>> {
+        use super::super::BlockStream;
+        use futures::StreamExt;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let mut server = MockServer::new_async().await;
+        let hash_requests = AtomicUsize::new(0);
+        // Block 1 is mined after the first request
+        server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::Regex("getblockhash".to_string()))
+            .with_body_from_request(move |_| {
+                match hash_requests.fetch_add(1, Ordering::SeqCst) {
+                    0 => r#"{"result":null,"error":{"code":-8,"message":"Block height out of range"},"id":1}"#,
+                    _ => r#"{"result":"hash1","error":null,"id":1}"#,
+                }
+                .into()
+            })
+            .create_async()
+            .await;
+        server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::Regex("getblock\"".to_string()))
+            .with_body(r#"{"result":{"height":1,"hash":"hash1","tx":[]},"error":null,"id":1}"#)
+            .create_async()
+            .await;
+
+        let client = HttpClientBuilder::new().build(server.url())?;
+        let mut stream = BlockStream::with_starting_block(client, 0);
+        let (block, height) = stream.next().await.expect("The stream doesn't end");
+        assert_eq!((block.hash.as_str(), height), ("hash1", 1));
+        Ok(())
+    }
 }
@@ -0,0 +1,344 @@
+use ff::{Field, PrimeField};
+use group::{cofactor::CofactorGroup, Curve, Group, GroupEncoding};
+use std::sync::OnceLock;
+
+use super::types::{CompactBlock, SaplingTree};
+use crate::error::PIVXErrors;
+
+/// Depth of the sapling note commitment tree
+pub const SAPLING_TREE_DEPTH: usize = 32;
+
+/// A tree node, that is the little endian representation of a jubjub base field element
+pub type Node = [u8; 32];
+
+const PEDERSEN_HASH_PERSONALIZATION: &[u8; 8] = b"Zcash_PH";
+const GROUP_HASH_FIRST_BLOCK: &[u8; 64] =
+    b"096b36a5804bfacef1691e173c366a47ff5ba84a44f26ddd7e8d9f79d5b42df0";
+const PEDERSEN_HASH_CHUNKS_PER_GENERATOR: usize = 63;
+/// A merkle hash takes 6 + 2 * 255 bits, which are 172 chunks of 3 bits
+const PEDERSEN_HASH_GENERATORS: usize = 3;
+
+fn group_hash(tag: &[u8]) -> Option<jubjub::SubgroupPoint> {
+    let hash = blake2s_simd::Params::new()
+        .hash_length(32)
+        .personal(PEDERSEN_HASH_PERSONALIZATION)
+        .to_state()
+        .update(GROUP_HASH_FIRST_BLOCK)
+        .update(tag)
+        .finalize();
+    let point: Option<jubjub::ExtendedPoint> =
+        jubjub::ExtendedPoint::from_bytes(hash.as_array()).into();
+    let point = point?.clear_cofactor();
+    if bool::from(point.is_identity()) {
+        None
+    } else {
+        Some(point)
+    }
+}
+
+fn find_group_hash(m: &[u8]) -> jubjub::SubgroupPoint {
+    let mut tag = m.to_vec();
+    tag.push(0);
+    loop {
+        if let Some(point) = group_hash(&tag) {
+            return point;
+        }
+        *tag.last_mut().unwrap() += 1;
+    }
+}
+
+fn generators() -> &'static [jubjub::SubgroupPoint] {
+    static GENERATORS: OnceLock<Vec<jubjub::SubgroupPoint>> = OnceLock::new();
+    GENERATORS.get_or_init(|| {
+        (0..PEDERSEN_HASH_GENERATORS as u32)
+            .map(|i| find_group_hash(&i.to_le_bytes()))
+            .collect()
+    })
+}
+
+fn pedersen_hash(bits: impl Iterator<Item = bool>) -> jubjub::SubgroupPoint {
+    let mut bits = bits.peekable();
+    let mut result = jubjub::SubgroupPoint::identity();
+    for generator in generators() {
+        if bits.peek().is_none() {
+            break;
+        }
+        let mut acc = jubjub::Fr::ZERO;
+        let mut cur = jubjub::Fr::ONE;
+        for _ in 0..PEDERSEN_HASH_CHUNKS_PER_GENERATOR {
+            let Some(a) = bits.next() else {
+                break;
+            };
+            let b = bits.next().unwrap_or(false);
+            let c = bits.next().unwrap_or(false);
+            let mut tmp = cur;
+            if a {
+                tmp += cur;
+            }
+            cur = cur.double();
+            if b {
+                tmp += cur;
+            }
+            if c {
+                tmp = -tmp;
+            }
+            acc += tmp;
+            cur = cur.double().double().double();
+        }
+        result += generator * acc;
+    }
+    result
+}
+
+fn to_bits(node: &Node) -> impl Iterator<Item = bool> + '_ {
+    node.iter()
+        .flat_map(|byte| (0..8).map(move |i| (byte >> i) & 1 == 1))
+        .take(jubjub::Fq::NUM_BITS as usize)
+}
+
+/**
+ * Hashes two nodes at `level` of the tree, where 0 is the level of the leaves
+ */
+pub fn merkle_hash(level: usize, left: &Node, right: &Node) -> Node {
+    let personalization = (0..6).map(|i| (level >> i) & 1 == 1);
+    let bits = personalization.chain(to_bits(left)).chain(to_bits(right));
+    jubjub::ExtendedPoint::from(pedersen_hash(bits))
+        .to_affine()
+        .get_u()
+        .to_repr()
+}
+
+/**
+ * Returns the root of an empty tree of depth `level`.
+ * The empty leaf is the field element 1
+ */
+pub fn empty_root(level: usize) -> Node {
+    static EMPTY_ROOTS: OnceLock<Vec<Node>> = OnceLock::new();
+    EMPTY_ROOTS.get_or_init(|| {
+        let mut roots = vec![jubjub::Fq::ONE.to_repr()];
+        for level in 0..SAPLING_TREE_DEPTH {
+            roots.push(merkle_hash(level, &roots[level], &roots[level]));
+        }
+        roots
+    })[level]
+}
+
+/// Frontier of the sapling note commitment tree,
+/// with the same layout and serialization as pivxd's `SaplingMerkleTree`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommitmentTree {
+    left: Option<Node>,
+    right: Option<Node>,
+    parents: Vec<Option<Node>>,
+}
+
+impl CommitmentTree {
+    pub fn append(&mut self, node: Node) {
+        let (Some(left), Some(right)) = (self.left, self.right) else {
+            if self.left.is_none() {
+                self.left = Some(node);
+            } else {
+                self.right = Some(node);
+            }
+            return;
+        };
+        let mut combined = merkle_hash(0, &left, &right);
+        self.left = Some(node);
+        self.right = None;
+        for (i, parent) in self.parents.iter_mut().enumerate() {
+            match parent.take() {
+                Some(parent) => combined = merkle_hash(i + 1, &parent, &combined),
+                None => {
+                    *parent = Some(combined);
+                    return;
+                }
+            }
+        }
+        self.parents.push(Some(combined));
+    }
+
+    pub fn root(&self) -> Node {
+        let empty_leaf = empty_root(0);
+        let mut root = merkle_hash(
+            0,
+            &self.left.unwrap_or(empty_leaf),
+            &self.right.unwrap_or(empty_leaf),
+        );
+        for level in 1..SAPLING_TREE_DEPTH {
+            root = match self.parents.get(level - 1) {
+                Some(Some(parent)) => merkle_hash(level, parent, &root),
+                _ => merkle_hash(level, &root, &empty_root(level)),
+            };
+        }
+        root
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        fn write_node(bytes: &mut Vec<u8>, node: &Option<Node>) {
+            match node {
+                Some(node) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(node);
+                }
+                None => bytes.push(0),
+            }
+        }
+        let mut bytes = vec![];
+        write_node(&mut bytes, &self.left);
+        write_node(&mut bytes, &self.right);
+        // The frontier has less than 253 parents, so the compact size is a single byte
+        bytes.push(self.parents.len() as u8);
+        for parent in &self.parents {
+            write_node(&mut bytes, parent);
+        }
+        bytes
+    }
+
+    pub fn deserialize(mut bytes: &[u8]) -> crate::error::Result<Self> {
+        fn read_byte(bytes: &mut &[u8]) -> crate::error::Result<u8> {
+            let (byte, rest) = bytes.split_first().ok_or(PIVXErrors::InvalidSaplingTree)?;
+            *bytes = rest;
+            Ok(*byte)
+        }
+        fn read_node(bytes: &mut &[u8]) -> crate::error::Result<Option<Node>> {
+            match read_byte(bytes)? {
+                0 => Ok(None),
+                1 if bytes.len() >= 32 => {
+                    let (node, rest) = bytes.split_at(32);
+                    *bytes = rest;
+                    Ok(Some(node.try_into().unwrap()))
+                }
+                _ => Err(PIVXErrors::InvalidSaplingTree),
+            }
+        }
+        let left = read_node(&mut bytes)?;
+        let right = read_node(&mut bytes)?;
+        let len = read_byte(&mut bytes)? as usize;
+        if len >= SAPLING_TREE_DEPTH {
+            return Err(PIVXErrors::InvalidSaplingTree);
+        }
+        let parents = (0..len)
+            .map(|_| read_node(&mut bytes))
+            .collect::<crate::error::Result<_>>()?;
+        if !bytes.is_empty() {
+            return Err(PIVXErrors::InvalidSaplingTree);
+        }
+        Ok(Self {
+            left,
+            right,
+            parents,
+        })
+    }
+}
+
+/// The commitment tree after appending the outputs of all blocks up to `height`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SaplingTreeState {
+    pub height: u64,
+    pub tree: CommitmentTree,
+}
+
+impl SaplingTreeState {
+    pub fn from_snapshot(snapshot: &SaplingTree) -> crate::error::Result<Self> {
+        let bytes = hex::decode(&snapshot.tree).map_err(|_| PIVXErrors::InvalidSaplingTree)?;
+        Ok(Self {
+            height: snapshot.height,
+            tree: CommitmentTree::deserialize(&bytes)?,
+        })
+    }
+
+    pub fn snapshot(&self) -> SaplingTree {
+        SaplingTree {
+            height: self.height,
+            root: hex::encode(self.tree.root()),
+            tree: hex::encode(self.tree.serialize()),
+        }
+    }
+
+    /**
+     * Appends the outputs of the block at `height`, which can be None if it has no shield txs.
+     * Blocks must be appended in order, blocks at or below `self.height` are ignored
+     */
+    pub fn append_block(
+        &mut self,
+        height: u64,
+        block: Option<&CompactBlock>,
+    ) -> crate::error::Result<()> {
+        if height <= self.height {
+            return Ok(());
+        }
+        let outputs = block
+            .iter()
+            .flat_map(|block| &block.txs)
+            .flat_map(|tx| &tx.outputs);
+        for output in outputs {
+            let cmu = hex::decode(&output.cmu)
+                .ok()
+                .and_then(|cmu| cmu.try_into().ok())
+                .ok_or(PIVXErrors::InvalidBlock)?;
+            self.tree.append(cmu);
+        }
+        self.height = height;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn node(hex: &str) -> Node {
+        hex::decode(hex).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn it_computes_empty_roots() {
+        assert_eq!(
+            empty_root(0),
+            node("0100000000000000000000000000000000000000000000000000000000000000")
+        );
+        assert_eq!(
+            empty_root(1),
+            node("817de36ab2d57feb077634bca77819c8e0bd298c04f6fed0e6a83cc1356ca155")
+        );
+        assert_eq!(
+            empty_root(2),
+            node("ffe9fc03f18b176c998806439ff0bb8ad193afdb27b2ccbc88856916dd804e34")
+        );
+        assert_eq!(CommitmentTree::default().root(), empty_root(32));
+    }
+
+    #[test]
+    fn it_appends_nodes() -> crate::error::Result<()> {
+        let leaves: Vec<Node> = (2..7).map(|i| [i; 32].map(|b| b & 0x0f)).collect();
+        let mut tree = CommitmentTree::default();
+        for leaf in &leaves {
+            tree.append(*leaf);
+        }
+        // 5 leaves: (0, 1), (2, 3) are combined into a parent of level 2,
+        // and the last leaf is left alone
+        let level1 = merkle_hash(0, &leaves[2], &leaves[3]);
+        let level2 = merkle_hash(1, &merkle_hash(0, &leaves[0], &leaves[1]), &level1);
+        assert_eq!(
+            tree,
+            CommitmentTree {
+                left: Some(leaves[4]),
+                right: None,
+                parents: vec![None, Some(level2)],
+            }
+        );
+        let mut root = merkle_hash(0, &leaves[4], &empty_root(0));
+        root = merkle_hash(1, &root, &empty_root(1));
+        root = merkle_hash(2, &level2, &root);
+        for level in 3..SAPLING_TREE_DEPTH {
+            root = merkle_hash(level, &root, &empty_root(level));
+        }
+        assert_eq!(tree.root(), root);
+
+        let serialized = tree.serialize();
+        assert_eq!(serialized.len(), 1 + 32 + 1 + 1 + 1 + 1 + 32);
+        assert_eq!(CommitmentTree::deserialize(&serialized)?, tree);
+        assert!(CommitmentTree::deserialize(&serialized[1..]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+use crate::address::{Address, AddressType, Network};
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+
+const OP_0: u8 = 0x00;
+const OP_PUSHDATA1: u8 = 0x4c;
+const OP_PUSHDATA2: u8 = 0x4d;
+const OP_PUSHDATA4: u8 = 0x4e;
+const OP_1: u8 = 0x51;
+const OP_16: u8 = 0x60;
+const OP_IF: u8 = 0x63;
+const OP_ELSE: u8 = 0x67;
+const OP_ENDIF: u8 = 0x68;
+const OP_RETURN: u8 = 0x6a;
+const OP_ROT: u8 = 0x7b;
+const OP_DUP: u8 = 0x76;
+const OP_EQUAL: u8 = 0x87;
+const OP_EQUALVERIFY: u8 = 0x88;
+const OP_HASH160: u8 = 0xa9;
+const OP_CHECKSIG: u8 = 0xac;
+const OP_CHECKMULTISIG: u8 = 0xae;
+const OP_ZEROCOINMINT: u8 = 0xc1;
+const OP_ZEROCOINSPEND: u8 = 0xc2;
+const OP_ZEROCOINPUBLICSPEND: u8 = 0xc3;
+const OP_CHECKCOLDSTAKEVERIFY_LOF: u8 = 0xd1;
+const OP_CHECKCOLDSTAKEVERIFY: u8 = 0xd2;
+const OP_EXCHANGEADDR: u8 = 0xe0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Op<'a> {
+    Push(&'a [u8]),
+    Code(u8),
+}
+
+/**
+ * Type of a scriptPubKey, with the data needed to derive its destinations
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptKind {
+    PubKeyHash([u8; 20]),
+    ScriptHash([u8; 20]),
+    PubKey(Vec<u8>),
+    MultiSig { required: u8, pubkeys: Vec<Vec<u8>> },
+    ColdStake { staker: [u8; 20], owner: [u8; 20] },
+    ExchangeAddress([u8; 20]),
+    NullData(Vec<u8>),
+    ZerocoinMint,
+    ZerocoinSpend,
+    NonStandard,
+}
+
+impl ScriptKind {
+    /**
+     * Splits a script into pushes and opcodes.
+     * Returns None if a push goes past the end of the script.
+     */
+    fn parse(script: &[u8]) -> Option<Vec<Op<'_>>> {
+        let mut ops = vec![];
+        let mut i = 0;
+        while i < script.len() {
+            let opcode = script[i];
+            i += 1;
+            let push_length = match opcode {
+                0x01..=0x4b => opcode as usize,
+                OP_PUSHDATA1 => {
+                    let length = *script.get(i)? as usize;
+                    i += 1;
+                    length
+                }
+                OP_PUSHDATA2 => {
+                    let length = u16::from_le_bytes(script.get(i..i + 2)?.try_into().ok()?);
+                    i += 2;
+                    length as usize
+                }
+                OP_PUSHDATA4 => {
+                    let length = u32::from_le_bytes(script.get(i..i + 4)?.try_into().ok()?);
+                    i += 4;
+                    length as usize
+                }
+                OP_0 => {
+                    ops.push(Op::Push(&[]));
+                    continue;
+                }
+                _ => {
+                    ops.push(Op::Code(opcode));
+                    continue;
+                }
+            };
+            ops.push(Op::Push(script.get(i..i.checked_add(push_length)?)?));
+            i += push_length;
+        }
+        Some(ops)
+    }
+
+    fn small_int(op: &Op) -> Option<u8> {
+        match op {
+            Op::Code(code @ OP_1..=OP_16) => Some(code - OP_1 + 1),
+            _ => None,
+        }
+    }
+
+    fn is_pubkey(data: &[u8]) -> bool {
+        match data.len() {
+            33 => data[0] == 0x02 || data[0] == 0x03,
+            65 => data[0] == 0x04,
+            _ => false,
+        }
+    }
+
+    fn get_multisig(ops: &[Op]) -> Option<Self> {
+        let (first, rest) = ops.split_first()?;
+        let (last, rest) = rest.split_last()?;
+        let (n, keys) = rest.split_last()?;
+        if *last != Op::Code(OP_CHECKMULTISIG) {
+            return None;
+        }
+        let required = Self::small_int(first)?;
+        let n = Self::small_int(n)?;
+        let pubkeys = keys
+            .iter()
+            .map(|op| match op {
+                Op::Push(data) if Self::is_pubkey(data) => Some(data.to_vec()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        if pubkeys.len() != n as usize || required > n {
+            return None;
+        }
+        Some(Self::MultiSig { required, pubkeys })
+    }
+
+    /**
+     * Classifies a scriptPubKey by matching it against the standard templates.
+     */
+    pub fn classify(script: &[u8]) -> Self {
+        use Op::{Code, Push};
+        match script.first() {
+            Some(&OP_ZEROCOINMINT) => return Self::ZerocoinMint,
+            Some(&OP_ZEROCOINSPEND) | Some(&OP_ZEROCOINPUBLICSPEND) => return Self::ZerocoinSpend,
+            _ => {}
+        }
+        let Some(ops) = Self::parse(script) else {
+            return Self::NonStandard;
+        };
+        match ops.as_slice() {
+            [Code(OP_DUP), Code(OP_HASH160), Push(hash), Code(OP_EQUALVERIFY), Code(OP_CHECKSIG)]
+                if hash.len() == 20 =>
+            {
+                Self::PubKeyHash((*hash).try_into().unwrap())
+            }
+            [Code(OP_EXCHANGEADDR), Code(OP_DUP), Code(OP_HASH160), Push(hash), Code(OP_EQUALVERIFY), Code(OP_CHECKSIG)]
+                if hash.len() == 20 =>
+            {
+                Self::ExchangeAddress((*hash).try_into().unwrap())
+            }
+            [Code(OP_HASH160), Push(hash), Code(OP_EQUAL)] if hash.len() == 20 => {
+                Self::ScriptHash((*hash).try_into().unwrap())
+            }
+            [Push(pubkey), Code(OP_CHECKSIG)] if Self::is_pubkey(pubkey) => {
+                Self::PubKey(pubkey.to_vec())
+            }
+            [Code(OP_DUP), Code(OP_HASH160), Code(OP_ROT), Code(OP_IF), Code(OP_CHECKCOLDSTAKEVERIFY_LOF | OP_CHECKCOLDSTAKEVERIFY), Push(staker), Code(OP_ELSE), Push(owner), Code(OP_ENDIF), Code(OP_EQUALVERIFY), Code(OP_CHECKSIG)]
+                if staker.len() == 20 && owner.len() == 20 =>
+            {
+                Self::ColdStake {
+                    staker: (*staker).try_into().unwrap(),
+                    owner: (*owner).try_into().unwrap(),
+                }
+            }
+            [Code(OP_RETURN), data @ ..] => {
+                let mut bytes = vec![];
+                for op in data {
+                    match op {
+                        Push(push) => bytes.extend_from_slice(push),
+                        Code(_) => return Self::NonStandard,
+                    }
+                }
+                Self::NullData(bytes)
+            }
+            ops => Self::get_multisig(ops).unwrap_or(Self::NonStandard),
+        }
+    }
+
+    fn hash160(data: &[u8]) -> [u8; 20] {
+        Ripemd160::digest(Sha256::digest(data)).into()
+    }
+
+    /**
+     * Returns the addresses that should be indexed for this script.
+     * Pay to pubkey and multisig outputs are indexed under the P2PKH address of each key.
+     */
+    pub fn destinations(&self) -> Vec<String> {
+        let encode =
+            |address_type, hash: &[u8]| Address::new(address_type, Network::Mainnet, hash).encode();
+        match self {
+            Self::PubKeyHash(hash) => vec![encode(AddressType::PubKeyHash, hash)],
+            Self::ExchangeAddress(hash) => vec![encode(AddressType::Exchange, hash)],
+            Self::ScriptHash(hash) => vec![encode(AddressType::ScriptHash, hash)],
+            Self::PubKey(pubkey) => vec![encode(AddressType::PubKeyHash, &Self::hash160(pubkey))],
+            Self::MultiSig { pubkeys, .. } => pubkeys
+                .iter()
+                .map(|pubkey| encode(AddressType::PubKeyHash, &Self::hash160(pubkey)))
+                .collect(),
+            // Stakers are indexed separately, see `AddressExtractor::get_addresses_from_tx`
+            Self::ColdStake { owner, .. } => vec![encode(AddressType::PubKeyHash, owner)],
+            Self::NullData(_) | Self::ZerocoinMint | Self::ZerocoinSpend | Self::NonStandard => {
+                vec![]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn classify(script: &str) -> ScriptKind {
+        ScriptKind::classify(&hex::decode(script).unwrap())
+    }
+
+    #[test]
+    fn it_classifies_p2pkh() {
+        let kind = classify("76a9145b589b431bc3b563de290426ce74a70b6cc8c26b88ac");
+        assert!(matches!(kind, ScriptKind::PubKeyHash(_)));
+        assert_eq!(
+            kind.destinations(),
+            vec!["DDU6BCfxp2eGdQ5AuoyL4QQo6D4abms5qg"]
+        );
+    }
+
+    #[test]
+    fn it_classifies_p2cs() {
+        let kind = classify("76a97b63d114ea6a55bd6e5eeab8453ae897bf5be28a62465fcc67146bc3ffd106a5b56efba5f0da6dba47bca83b2bd76888ac");
+        assert!(matches!(kind, ScriptKind::ColdStake { .. }));
+        assert_eq!(
+            kind.destinations(),
+            vec!["DExue43LyQduJzkUwFq53LfSppAzdRGWU2"]
+        );
+    }
+
+    #[test]
+    fn it_classifies_p2sh() {
+        let kind = classify("a9145b589b431bc3b563de290426ce74a70b6cc8c26b87");
+        assert!(matches!(kind, ScriptKind::ScriptHash(_)));
+        assert_eq!(
+            kind.destinations(),
+            vec!["6NiqSMc4jxmNj2hhVgJupGnRPdgYZ9VPmm"]
+        );
+    }
+
+    #[test]
+    fn it_classifies_p2pk() {
+        let kind =
+            classify("210215912e6a40c2457b95ddc37ee592d3f4bc2ffc1e494861fbb3374e2edc414e1aac");
+        assert!(matches!(kind, ScriptKind::PubKey(_)));
+        assert_eq!(
+            kind.destinations(),
+            vec!["DDU6BCfxp2eGdQ5AuoyL4QQo6D4abms5qg"]
+        );
+    }
+
+    #[test]
+    fn it_classifies_multisig() {
+        let kind = classify("52210215912e6a40c2457b95ddc37ee592d3f4bc2ffc1e494861fbb3374e2edc414e1a210311b85ed73eafceae37768a64e949d45dfd6bbf9c76d02399b4bbddcdbcd9d12a52ae");
+        assert_eq!(
+            kind,
+            ScriptKind::MultiSig {
+                required: 2,
+                pubkeys: vec![
+                    hex::decode(
+                        "0215912e6a40c2457b95ddc37ee592d3f4bc2ffc1e494861fbb3374e2edc414e1a"
+                    )
+                    .unwrap(),
+                    hex::decode(
+                        "0311b85ed73eafceae37768a64e949d45dfd6bbf9c76d02399b4bbddcdbcd9d12a"
+                    )
+                    .unwrap(),
+                ]
+            }
+        );
+        assert_eq!(
+            kind.destinations(),
+            vec![
+                "DDU6BCfxp2eGdQ5AuoyL4QQo6D4abms5qg",
+                "DCzTFmo34LPyyGmwT3k3bXrdUoGZQ3wH16"
+            ]
+        );
+        // 3 of 2 is invalid
+        assert_eq!(
+            classify("53210215912e6a40c2457b95ddc37ee592d3f4bc2ffc1e494861fbb3374e2edc414e1a210311b85ed73eafceae37768a64e949d45dfd6bbf9c76d02399b4bbddcdbcd9d12a52ae"),
+            ScriptKind::NonStandard
+        );
+    }
+
+    #[test]
+    fn it_classifies_exchange_address() {
+        let kind = classify("e076a9145b589b431bc3b563de290426ce74a70b6cc8c26b88ac");
+        assert!(matches!(kind, ScriptKind::ExchangeAddress(_)));
+        assert_eq!(
+            kind.destinations(),
+            vec!["EXMKLh2d1jUdovx4xfF7Tye8uTSHvz3QUjBX"]
+        );
+    }
+
+    #[test]
+    fn it_classifies_null_data() {
+        assert_eq!(
+            classify("6a0568656c6c6f"),
+            ScriptKind::NullData(b"hello".to_vec())
+        );
+        assert_eq!(classify("6a"), ScriptKind::NullData(vec![]));
+        assert!(classify("6a0568656c6c6f").destinations().is_empty());
+    }
+
+    #[test]
+    fn it_classifies_zerocoin() {
+        assert_eq!(classify("c10280a1"), ScriptKind::ZerocoinMint);
+        assert_eq!(classify("c2"), ScriptKind::ZerocoinSpend);
+        assert_eq!(classify("c3"), ScriptKind::ZerocoinSpend);
+    }
+
+    #[test]
+    fn it_classifies_nonstandard() {
+        assert_eq!(classify(""), ScriptKind::NonStandard);
+        // Push past the end of the script
+        assert_eq!(classify("76a9155b589b43"), ScriptKind::NonStandard);
+        // P2PKH with trailing opcode
+        assert_eq!(
+            classify("76a9145b589b431bc3b563de290426ce74a70b6cc8c26b88ac51"),
+            ScriptKind::NonStandard
+        );
+    }
+}
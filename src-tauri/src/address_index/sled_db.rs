@@ -0,0 +1,558 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::PathBuf;
+
+use super::database::Database;
+use super::types::{
+    BlockHeader, CompactBlock, Delegation, RawTx, SaplingTree, SpentNullifier, Tx, Vin,
+};
+use crate::error::PIVXErrors;
+use serde::{de::DeserializeOwned, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{Batch, Db, IVec, Transactional, Tree};
+
+/// Version of the key layout. Indexes with an older one are wiped and synced again
+const SCHEMA_VERSION: u64 = 1;
+
+/// `Database` implementation on the sled embedded key-value store.
+/// Each table is a sled tree, multi-part keys are joined with a 0 byte
+/// and integers are big endian so that they sort numerically
+#[derive(Clone, Debug)]
+pub struct SledDb {
+    db: Db,
+    /// Tx whose write fails, see `FaultInjection`
+    #[cfg(test)]
+    failing_txid: Option<String>,
+}
+
+/// Batches of writes to several trees, applied together by `SledDb::apply`
+#[derive(Default)]
+struct Writes {
+    batches: BTreeMap<String, Batch>,
+    /// Aborts the transaction once the batches are written, see `FaultInjection`
+    #[cfg(test)]
+    fail: bool,
+}
+
+impl Writes {
+    fn insert(&mut self, tree: &str, key: impl Into<IVec>, value: impl Into<IVec>) {
+        self.batches
+            .entry(tree.to_owned())
+            .or_default()
+            .insert(key, value);
+    }
+
+    fn remove(&mut self, tree: &str, key: impl Into<IVec>) {
+        self.batches.entry(tree.to_owned()).or_default().remove(key);
+    }
+}
+
+fn key(parts: &[&[u8]]) -> Vec<u8> {
+    parts.join(&0)
+}
+
+/**
+ * Writes `bytes` prefixed by their length, as a little endian u32
+ */
+fn write_bytes(file: &mut impl Write, bytes: &[u8]) -> crate::error::Result<()> {
+    file.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    file.write_all(bytes)?;
+    Ok(())
+}
+
+/**
+ * Reads bytes written by `write_bytes`, returns None at the end of the file
+ */
+fn read_bytes(file: &mut impl Read) -> crate::error::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match file.read_exact(&mut len) {
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let mut bytes = vec![0; u32::from_le_bytes(len) as usize];
+    file.read_exact(&mut bytes)?;
+    Ok(Some(bytes))
+}
+
+fn to_json<T: Serialize>(value: &T) -> crate::error::Result<Vec<u8>> {
+    Ok(serde_json::to_vec(value)?)
+}
+
+fn from_json<T: DeserializeOwned>(bytes: &[u8]) -> crate::error::Result<T> {
+    Ok(serde_json::from_slice(bytes)?)
+}
+
+impl SledDb {
+    pub async fn new(path: PathBuf) -> crate::error::Result<Self> {
+        tauri::async_runtime::spawn_blocking(move || {
+            let sled_db = Self {
+                db: sled::open(path)?,
+                #[cfg(test)]
+                failing_txid: None,
+            };
+            sled_db.migrate()?;
+            Ok(sled_db)
+        })
+        .await?
+    }
+
+    /**
+     * Wipes an index written with an older key layout, so that it's synced again
+     */
+    fn migrate(&self) -> crate::error::Result<()> {
+        let version = self.get_config("SCHEMA_VERSION")?.unwrap_or(0);
+        if version > SCHEMA_VERSION {
+            return Err(PIVXErrors::IndexTooNew);
+        }
+        if version < SCHEMA_VERSION {
+            if self.get_config("BLOCK_COUNT")?.is_some() {
+                println!("Index schema {} is outdated, reindexing", version);
+            }
+            self.clear_index()?;
+            self.tree("config")?
+                .insert("SCHEMA_VERSION", &SCHEMA_VERSION.to_be_bytes())?;
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Deletes all indexed data, which is everything but the config other than the block count
+     */
+    fn clear_index(&self) -> crate::error::Result<()> {
+        for name in self.db.tree_names() {
+            if name != "config" {
+                self.db.open_tree(name)?.clear()?;
+            }
+        }
+        self.tree("config")?.remove("BLOCK_COUNT")?;
+        Ok(())
+    }
+
+    fn tree(&self, name: &str) -> crate::error::Result<Tree> {
+        Ok(self.db.open_tree(name)?)
+    }
+
+    fn get_config(&self, key: &str) -> crate::error::Result<Option<u64>> {
+        Ok(self
+            .tree("config")?
+            .get(key)?
+            .and_then(|value| value.as_ref().try_into().ok())
+            .map(u64::from_be_bytes))
+    }
+
+    /**
+     * Applies the writes to all their trees in one transaction,
+     * so that a crash never leaves only some of the trees written
+     */
+    fn apply(&self, writes: Writes) -> crate::error::Result<()> {
+        let trees = writes
+            .batches
+            .keys()
+            .map(|name| self.tree(name))
+            .collect::<crate::error::Result<Vec<_>>>()?;
+        let trees: Vec<&Tree> = trees.iter().collect();
+        trees
+            .as_slice()
+            .transaction(|trees| {
+                for (tree, batch) in trees.iter().zip(writes.batches.values()) {
+                    tree.apply_batch(batch)?;
+                }
+                #[cfg(test)]
+                if writes.fail {
+                    let err = std::io::Error::other("Injected write failure");
+                    return Err(ConflictableTransactionError::Abort(err.into()));
+                }
+                Ok::<_, ConflictableTransactionError<PIVXErrors>>(())
+            })
+            .map_err(|err| match err {
+                TransactionError::Abort(err) => err,
+                TransactionError::Storage(err) => err.into(),
+            })
+    }
+
+    fn get_json<T: DeserializeOwned>(
+        &self,
+        tree: &str,
+        key: &[u8],
+    ) -> crate::error::Result<Option<T>> {
+        self.tree(tree)?
+            .get(key)?
+            .map(|value| from_json(&value))
+            .transpose()
+    }
+
+    fn get_header_at(&self, height: &[u8]) -> crate::error::Result<Option<BlockHeader>> {
+        self.get_json("headers", height)
+    }
+}
+
+impl Database for SledDb {
+    async fn get_address_txids(&self, address: &str) -> crate::error::Result<Vec<String>> {
+        // Keyed by a sequence number after the address, so txids are in the order they were stored
+        self.tree("transactions")?
+            .scan_prefix(key(&[address.as_bytes(), b""]))
+            .values()
+            .map(|txid| Ok(String::from_utf8_lossy(&txid?).into_owned()))
+            .collect()
+    }
+
+    async fn get_delegations(&self, staker: &str) -> crate::error::Result<Vec<Delegation>> {
+        let prefix = key(&[staker.as_bytes(), b""]);
+        let vin = self.tree("vin")?;
+        let mut delegations = vec![];
+        for entry in self.tree("delegations_by_staker")?.scan_prefix(&prefix) {
+            let (_, outpoint) = entry?;
+            if vin.contains_key(&outpoint)? {
+                continue;
+            }
+            if let Some(delegation) = self.get_json("delegations", &outpoint)? {
+                delegations.push(delegation);
+            }
+        }
+        Ok(delegations)
+    }
+
+    async fn get_compact_blocks(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> crate::error::Result<Vec<CompactBlock>> {
+        if from > to {
+            return Ok(vec![]);
+        }
+        self.tree("compact_blocks")?
+            .range(from.to_be_bytes()..=to.to_be_bytes())
+            .values()
+            .map(|block| from_json(&block?))
+            .collect()
+    }
+
+    async fn store_compact_blocks<I>(&mut self, blocks: I) -> crate::error::Result<()>
+    where
+        I: Iterator<Item = CompactBlock>,
+    {
+        let mut writes = Writes::default();
+        for block in blocks {
+            for spent in block.get_spent_nullifiers() {
+                writes.insert("nullifiers", spent.nullifier.as_bytes(), to_json(&spent)?);
+            }
+            writes.insert(
+                "compact_blocks",
+                &block.height.to_be_bytes(),
+                to_json(&block)?,
+            );
+        }
+        self.apply(writes)
+    }
+
+    async fn get_spent_nullifiers(
+        &self,
+        nullifiers: &[&str],
+    ) -> crate::error::Result<Vec<SpentNullifier>> {
+        let mut spent = vec![];
+        for nullifier in nullifiers {
+            spent.extend(self.get_json("nullifiers", nullifier.as_bytes())?);
+        }
+        Ok(spent)
+    }
+
+    async fn get_sapling_tree(&self, height: u64) -> crate::error::Result<Option<SaplingTree>> {
+        self.tree("sapling_trees")?
+            .range(..=height.to_be_bytes())
+            .values()
+            .next_back()
+            .map(|tree| from_json(&tree?))
+            .transpose()
+    }
+
+    async fn store_sapling_trees<I>(&mut self, trees: I) -> crate::error::Result<()>
+    where
+        I: Iterator<Item = SaplingTree>,
+    {
+        let mut batch = Batch::default();
+        for tree in trees {
+            batch.insert(&tree.height.to_be_bytes(), to_json(&tree)?);
+        }
+        self.tree("sapling_trees")?.apply_batch(batch)?;
+        Ok(())
+    }
+
+    async fn get_header(&self, height: u64) -> crate::error::Result<Option<BlockHeader>> {
+        self.get_header_at(&height.to_be_bytes())
+    }
+
+    async fn get_header_by_hash(&self, hash: &str) -> crate::error::Result<Option<BlockHeader>> {
+        match self.tree("headers_by_hash")?.get(hash)? {
+            Some(height) => self.get_header_at(&height),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_header_at_time(&self, time: u64) -> crate::error::Result<Option<BlockHeader>> {
+        let end = key(&[&time.to_be_bytes(), &u64::MAX.to_be_bytes()]);
+        match self
+            .tree("headers_by_time")?
+            .range(..=end)
+            .values()
+            .next_back()
+        {
+            Some(height) => self.get_header_at(&height?),
+            None => Ok(None),
+        }
+    }
+
+    async fn store_headers<I>(&mut self, headers: I) -> crate::error::Result<()>
+    where
+        I: Iterator<Item = BlockHeader>,
+    {
+        let raw_txs_by_height = self.tree("raw_txs_by_height")?;
+        let mut writes = Writes::default();
+        for header in headers {
+            let height = header.height.to_be_bytes();
+            if let Some(old) = self.get_header_at(&height)? {
+                writes.remove("headers_by_hash", old.hash.as_bytes());
+                writes.remove("headers_by_time", key(&[&old.time.to_be_bytes(), &height]));
+            }
+            for entry in raw_txs_by_height.scan_prefix(key(&[&height, b""])) {
+                let (index_key, txid) = entry?;
+                let raw_tx: Option<RawTx> = self.get_json("raw_txs", &txid)?;
+                if !matches!(raw_tx, Some(raw_tx) if raw_tx.block_hash == header.hash) {
+                    writes.remove("raw_txs", txid);
+                    writes.remove("raw_txs_by_height", index_key);
+                }
+            }
+            writes.insert("headers_by_hash", header.hash.as_bytes(), &height);
+            writes.insert(
+                "headers_by_time",
+                key(&[&header.time.to_be_bytes(), &height]),
+                &height,
+            );
+            writes.insert("headers", &height, to_json(&header)?);
+        }
+        self.apply(writes)
+    }
+
+    async fn get_raw_tx(&self, txid: &str) -> crate::error::Result<Option<RawTx>> {
+        self.get_json("raw_txs", txid.as_bytes())
+    }
+
+    async fn store_raw_tx(&mut self, raw_tx: &RawTx) -> crate::error::Result<()> {
+        let height = raw_tx.height.to_be_bytes();
+        let txid = raw_tx.txid.as_bytes();
+        self.tree("raw_txs")?.insert(txid, to_json(raw_tx)?)?;
+        self.tree("raw_txs_by_height")?
+            .insert(key(&[&height, txid]), txid)?;
+        Ok(())
+    }
+
+    async fn store_tx(&mut self, tx: &Tx) -> crate::error::Result<()> {
+        self.store_txs(std::iter::once(tx.clone())).await
+    }
+
+    async fn store_txs<I>(&mut self, txs: I) -> crate::error::Result<()>
+    where
+        I: Iterator<Item = Tx>,
+    {
+        let transaction_seqs = self.tree("transaction_seqs")?;
+        // Sequence numbers given in this batch, which is not applied yet
+        let mut new_seqs: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+        let mut writes = Writes::default();
+        for tx in txs {
+            let txid = tx.txid.as_bytes();
+            // The keys written for the tx, so that `rewind` can delete them
+            let mut tx_keys: Vec<(&str, Vec<u8>)> = vec![];
+            let stakers = tx.delegations.iter().map(|d| &d.staker);
+            for address in tx.addresses.iter().chain(stakers) {
+                // Txids of an address are keyed by the order they were first stored in
+                let seq_key = key(&[address.as_bytes(), txid]);
+                let stored = match new_seqs.get(&seq_key) {
+                    Some(seq) => Some(seq.clone()),
+                    None => transaction_seqs.get(&seq_key)?.map(|seq| seq.to_vec()),
+                };
+                let seq = match stored {
+                    Some(seq) => seq,
+                    None => {
+                        let seq = self.db.generate_id()?.to_be_bytes().to_vec();
+                        writes.insert("transactions", key(&[address.as_bytes(), &seq]), txid);
+                        writes.insert("transaction_seqs", seq_key.as_slice(), seq.as_slice());
+                        new_seqs.insert(seq_key.clone(), seq.clone());
+                        seq
+                    }
+                };
+                tx_keys.push(("transactions", key(&[address.as_bytes(), &seq])));
+                tx_keys.push(("transaction_seqs", seq_key));
+            }
+            for spent in &tx.vin {
+                let outpoint = key(&[spent.txid.as_bytes(), &spent.n.to_be_bytes()]);
+                writes.insert("vin", outpoint.as_slice(), txid);
+                tx_keys.push(("vin", outpoint));
+            }
+            for delegation in &tx.delegations {
+                let outpoint = key(&[txid, &delegation.n.to_be_bytes()]);
+                let by_staker = key(&[delegation.staker.as_bytes(), &outpoint]);
+                writes.insert(
+                    "delegations_by_staker",
+                    by_staker.as_slice(),
+                    outpoint.as_slice(),
+                );
+                writes.insert("delegations", outpoint.as_slice(), to_json(delegation)?);
+                tx_keys.push(("delegations_by_staker", by_staker));
+                tx_keys.push(("delegations", outpoint));
+            }
+            if let Some(height) = tx.height {
+                writes.insert(
+                    "tx_keys_by_height",
+                    key(&[&height.to_be_bytes(), txid]),
+                    to_json(&tx_keys)?,
+                );
+            }
+            #[cfg(test)]
+            if self.failing_txid.as_ref() == Some(&tx.txid) {
+                writes.fail = true;
+                break;
+            }
+        }
+        self.apply(writes)
+    }
+
+    async fn get_txid_from_vin(&self, vin: &Vin) -> crate::error::Result<Option<String>> {
+        let outpoint = key(&[vin.txid.as_bytes(), &vin.n.to_be_bytes()]);
+        Ok(self
+            .tree("vin")?
+            .get(outpoint)?
+            .map(|txid| String::from_utf8_lossy(&txid).into_owned()))
+    }
+
+    async fn update_block_count(&mut self, block_count: u64) -> crate::error::Result<()> {
+        let block_count = block_count.max(self.get_last_indexed_block().await?);
+        self.tree("config")?
+            .insert("BLOCK_COUNT", &block_count.to_be_bytes())?;
+        // Unlike sqlite, writes are not durable until flushed
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    async fn rewind(&mut self, height: u64) -> crate::error::Result<()> {
+        let above = (height + 1).to_be_bytes();
+        let mut writes = Writes::default();
+        for entry in self.tree("headers")?.range(above..) {
+            let (key, header) = entry?;
+            let header: BlockHeader = from_json(&header)?;
+            writes.remove("headers_by_hash", header.hash.as_bytes());
+            writes.remove(
+                "headers_by_time",
+                self::key(&[&header.time.to_be_bytes(), &key]),
+            );
+            writes.remove("headers", key);
+        }
+        for tree in ["compact_blocks", "sapling_trees"] {
+            for key in self.tree(tree)?.range(above..).keys() {
+                writes.remove(tree, key?);
+            }
+        }
+        for entry in self.tree("nullifiers")?.iter() {
+            let (key, spent) = entry?;
+            let spent: SpentNullifier = from_json(&spent)?;
+            if spent.height > height {
+                writes.remove("nullifiers", key);
+            }
+        }
+        for entry in self.tree("raw_txs_by_height")?.range(above..) {
+            let (key, txid) = entry?;
+            writes.remove("raw_txs", txid);
+            writes.remove("raw_txs_by_height", key);
+        }
+        for entry in self.tree("tx_keys_by_height")?.range(above..) {
+            let (key, tx_keys) = entry?;
+            let tx_keys: Vec<(String, Vec<u8>)> = from_json(&tx_keys)?;
+            for (tree, tx_key) in tx_keys {
+                writes.remove(&tree, tx_key);
+            }
+            writes.remove("tx_keys_by_height", key);
+        }
+        writes.insert("config", "BLOCK_COUNT", &height.to_be_bytes());
+        self.apply(writes)?;
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    async fn wipe(&mut self) -> crate::error::Result<()> {
+        self.clear_index()?;
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    async fn backup(&self, path: PathBuf) -> crate::error::Result<()> {
+        // The backup is a sequence of (tree name, key, value) entries
+        let db = self.db.clone();
+        tauri::async_runtime::spawn_blocking(move || {
+            let mut file = BufWriter::new(File::create(path)?);
+            for name in db.tree_names() {
+                for entry in db.open_tree(&name)?.iter() {
+                    let (key, value) = entry?;
+                    for bytes in [&name, &key, &value] {
+                        write_bytes(&mut file, bytes)?;
+                    }
+                }
+            }
+            file.flush()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn restore(&mut self, path: PathBuf) -> crate::error::Result<()> {
+        let db = self.db.clone();
+        tauri::async_runtime::spawn_blocking(move || -> crate::error::Result<()> {
+            let mut file = BufReader::new(File::open(path)?);
+            for name in db.tree_names() {
+                db.open_tree(name)?.clear()?;
+            }
+            while let Some(name) = read_bytes(&mut file)? {
+                let key = read_bytes(&mut file)?.ok_or(PIVXErrors::InvalidSnapshot)?;
+                let value = read_bytes(&mut file)?.ok_or(PIVXErrors::InvalidSnapshot)?;
+                db.open_tree(name)?.insert(key, value)?;
+            }
+            Ok(())
+        })
+        .await??;
+        // A backup with another key layout can't be read
+        if self.get_config("SCHEMA_VERSION")? != Some(SCHEMA_VERSION) {
+            self.clear_index()?;
+            self.tree("config")?
+                .insert("SCHEMA_VERSION", &SCHEMA_VERSION.to_be_bytes())?;
+            self.db.flush_async().await?;
+            return Err(PIVXErrors::InvalidSnapshot);
+        }
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    async fn get_last_indexed_block(&self) -> crate::error::Result<u64> {
+        Ok(self.get_config("BLOCK_COUNT")?.unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::database::conformance::{conformance_tests, FaultInjection};
+    use super::*;
+    use tempdir::TempDir;
+
+    async fn new_sled_db() -> crate::error::Result<(TempDir, SledDb)> {
+        let temp_dir = TempDir::new("sled-test")?;
+        let sled_db = SledDb::new(temp_dir.path().join("test.sled")).await?;
+        Ok((temp_dir, sled_db))
+    }
+
+    impl FaultInjection for SledDb {
+        async fn fail_writes_of(&mut self, txid: &str) -> crate::error::Result<()> {
+            self.failing_txid = Some(txid.to_owned());
+            Ok(())
+        }
+    }
+
+    conformance_tests!(new_sled_db);
+}
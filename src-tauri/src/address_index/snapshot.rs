@@ -0,0 +1,109 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use super::types::SnapshotManifest;
+use crate::error::PIVXErrors;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use tar::{Archive, Builder, Header};
+
+const MANIFEST: &str = "manifest.json";
+const INDEX: &str = "index";
+
+/// Removes the file at its path when dropped, also when an import or export fails
+pub struct TempFile(pub PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/**
+ * Returns the path of the database backup written while creating or reading the snapshot at `path`
+ */
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".partial");
+    PathBuf::from(name)
+}
+
+/**
+ * Writes a gzipped tar at `path` with the manifest and the database backup at `backup`
+ */
+pub fn write_snapshot(
+    path: &Path,
+    manifest: &SnapshotManifest,
+    backup: &Path,
+) -> crate::error::Result<()> {
+    let mut builder = Builder::new(GzEncoder::new(File::create(path)?, Compression::default()));
+    let manifest = serde_json::to_vec(manifest)?;
+    let mut header = Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST, manifest.as_slice())?;
+    builder.append_path_with_name(backup, INDEX)?;
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+/**
+ * Extracts the database backup of the snapshot at `path` to `backup`
+ * and checks it against the checksum in the manifest
+ */
+pub fn read_snapshot(path: &Path, backup: &Path) -> crate::error::Result<SnapshotManifest> {
+    let mut archive = Archive::new(GzDecoder::new(File::open(path)?));
+    let mut manifest: Option<SnapshotManifest> = None;
+    let mut has_index = false;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        // Links and directories could make `unpack` write outside of `backup`
+        if !entry.header().entry_type().is_file() {
+            return Err(PIVXErrors::InvalidSnapshot);
+        }
+        let name = entry.path()?.to_string_lossy().into_owned();
+        match name.as_str() {
+            MANIFEST => manifest = Some(serde_json::from_reader(&mut entry)?),
+            INDEX => {
+                // The entry is unpacked to our own path, never to the one in the archive
+                entry.unpack(backup)?;
+                has_index = true;
+            }
+            _ => return Err(PIVXErrors::InvalidSnapshot),
+        }
+    }
+    let manifest = manifest.ok_or(PIVXErrors::InvalidSnapshot)?;
+    if !has_index || sha256::try_digest(backup)? != manifest.sha256 {
+        return Err(PIVXErrors::InvalidSnapshot);
+    }
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tar::EntryType;
+
+    #[test]
+    fn it_rejects_links() -> crate::error::Result<()> {
+        let temp_dir = tempdir::TempDir::new("snapshot")?;
+        let path = temp_dir.path().join("snapshot.tar.gz");
+        let mut builder =
+            Builder::new(GzEncoder::new(File::create(&path)?, Compression::default()));
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o644);
+        builder.append_link(&mut header, INDEX, "/etc/passwd")?;
+        builder.into_inner()?.finish()?;
+
+        let backup = temp_dir.path().join("backup");
+        assert!(matches!(
+            read_snapshot(&path, &backup),
+            Err(PIVXErrors::InvalidSnapshot)
+        ));
+        assert!(std::fs::symlink_metadata(&backup).is_err());
+        Ok(())
+    }
+}
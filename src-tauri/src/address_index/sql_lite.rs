@@ -1,48 +1,542 @@
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 
 use super::database::Database;
-use super::types::{Tx, Vin};
-use rusqlite::{params, Connection};
+use super::types::{
+    BlockHeader, CompactBlock, CompactTx, Delegation, RawTx, SaplingTree, SpentNullifier, Tx, Vin,
+};
+use crate::error::PIVXErrors;
+use rusqlite::{params, Connection, OptionalExtension};
 
+/// All queries run on the blocking thread pool, so they never stall the async runtime.
+/// Writes and reads use separate connections: with WAL, readers see the last commit
+/// and don't have to wait for the chunk of blocks being indexed
 #[derive(Clone, Debug)]
 pub struct SqlLite {
-    path: PathBuf,
-    //connection: Connection,
+    writer: Arc<Mutex<Connection>>,
+    reader: Arc<Mutex<Connection>>,
+}
+
+fn lock(connection: &Mutex<Connection>) -> MutexGuard<'_, Connection> {
+    // The lock can only be poisoned by a panic in the middle of a transaction,
+    // which is rolled back when dropped, so the connection is still usable
+    connection.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn open(path: &PathBuf) -> crate::error::Result<Connection> {
+    let connection = Connection::open(path)?;
+    // Opening a connection is slow and drops the statement cache,
+    // so connections are kept for the lifetime of the database
+    connection.set_prepared_statement_cache_capacity(64);
+    Ok(connection)
+}
+
+fn query_header<P: rusqlite::Params>(
+    connection: &Connection,
+    condition: &str,
+    params: P,
+) -> crate::error::Result<Option<BlockHeader>> {
+    let mut stmt = connection.prepare_cached(&format!(
+        "SELECT height, hash, prev_hash, time, tx_count, size, is_proof_of_stake FROM headers {};",
+        condition
+    ))?;
+    let mut rows = stmt.query(params)?;
+    if let Some(row) = rows.next()? {
+        Ok(Some(BlockHeader {
+            height: row.get(0)?,
+            hash: row.get(1)?,
+            prev_hash: row.get(2)?,
+            time: row.get(3)?,
+            tx_count: row.get(4)?,
+            size: row.get(5)?,
+            is_proof_of_stake: row.get(6)?,
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+/// A schema change, applied in order by `migrate`
+enum Migration {
+    Sql(&'static str),
+    /// A change that needs data from blocks that were already indexed,
+    /// so the index is wiped and synced again
+    Reindex(&'static str),
+}
+
+/// The schema version of an index is the number of migrations applied to it.
+/// Never edit a migration that was released, add a new one instead
+const MIGRATIONS: &[Migration] = &[
+    // 1: Address index
+    Migration::Sql("
+CREATE TABLE transactions(txid TEXT NOT NULL, address TEXT NOT NULL, PRIMARY KEY (txid, address));
+CREATE TABLE vin(txid TEXT NOT NULL, n INTEGER NOT NULL, spender_txid TEXT NOT NULL, PRIMARY KEY (txid, n));
+CREATE INDEX idx_address ON transactions (address);
+"),
+    // 2: Cold staking, stakers are indexed along with the owners
+    Migration::Reindex("
+DROP TABLE transactions;
+CREATE TABLE transactions(txid TEXT NOT NULL, address TEXT NOT NULL, role TEXT NOT NULL DEFAULT 'owner', PRIMARY KEY (txid, address, role));
+CREATE INDEX idx_address ON transactions (address);
+DROP TABLE IF EXISTS delegations;
+CREATE TABLE delegations(txid TEXT NOT NULL, n INTEGER NOT NULL, value INTEGER NOT NULL, staker TEXT NOT NULL, owner TEXT NOT NULL, PRIMARY KEY (txid, n));
+CREATE INDEX idx_staker ON delegations (staker);
+"),
+    // 3: Shield sync, block headers and raw tx cache
+    Migration::Reindex("
+DROP TABLE IF EXISTS compact_blocks;
+DROP TABLE IF EXISTS nullifiers;
+DROP TABLE IF EXISTS sapling_trees;
+DROP TABLE IF EXISTS headers;
+DROP TABLE IF EXISTS raw_txs;
+CREATE TABLE compact_blocks(height INTEGER NOT NULL PRIMARY KEY, hash TEXT NOT NULL, time INTEGER NOT NULL, txs TEXT NOT NULL);
+CREATE TABLE nullifiers(nullifier TEXT NOT NULL PRIMARY KEY, txid TEXT NOT NULL, height INTEGER NOT NULL);
+CREATE TABLE sapling_trees(height INTEGER NOT NULL PRIMARY KEY, root TEXT NOT NULL, tree TEXT NOT NULL);
+CREATE TABLE headers(height INTEGER NOT NULL PRIMARY KEY, hash TEXT NOT NULL, prev_hash TEXT NOT NULL, time INTEGER NOT NULL, tx_count INTEGER NOT NULL, size INTEGER NOT NULL, is_proof_of_stake INTEGER NOT NULL);
+CREATE TABLE raw_txs(txid TEXT NOT NULL PRIMARY KEY, hex TEXT NOT NULL, block_hash TEXT NOT NULL, height INTEGER NOT NULL, time INTEGER NOT NULL);
+CREATE INDEX idx_hash ON headers (hash);
+CREATE INDEX idx_time ON headers (time);
+CREATE INDEX idx_raw_tx_height ON raw_txs (height);
+"),
+    // 4: Height of the address, vin and delegation rows, so that they can be rewound
+    Migration::Reindex("
+ALTER TABLE transactions ADD COLUMN height INTEGER;
+ALTER TABLE vin ADD COLUMN height INTEGER;
+ALTER TABLE delegations ADD COLUMN height INTEGER;
+CREATE INDEX idx_tx_height ON transactions (height);
+CREATE INDEX idx_vin_height ON vin (height);
+CREATE INDEX idx_delegation_height ON delegations (height);
+"),
+];
+
+pub const SCHEMA_VERSION: u64 = MIGRATIONS.len() as u64;
+
+fn schema_version(connection: &Connection) -> crate::error::Result<u64> {
+    let version = connection
+        .query_row(
+            "SELECT value FROM config WHERE key='SCHEMA_VERSION';",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if let Some(version) = version {
+        return Ok(version);
+    }
+    // Indexes created before migrations were introduced have the first schema
+    let has_transactions: bool = connection.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type='table' AND name='transactions';",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(if has_transactions { 1 } else { 0 })
+}
+
+/**
+ * Returns the names of the tables holding indexed data, which is everything but config
+ */
+fn index_tables(connection: &Connection) -> crate::error::Result<Vec<String>> {
+    Ok(connection
+        .prepare("SELECT name FROM sqlite_master WHERE type='table' AND name!='config' AND name NOT LIKE 'sqlite_%';")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+/**
+ * Copies every table of the database attached as `snapshot` over the main one.
+ * The snapshot must have the same schema version
+ */
+fn restore_attached(connection: &mut Connection) -> crate::error::Result<()> {
+    let version: Option<u64> = connection
+        .query_row(
+            "SELECT value FROM snapshot.config WHERE key='SCHEMA_VERSION';",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if version != Some(SCHEMA_VERSION) {
+        return Err(PIVXErrors::InvalidSnapshot);
+    }
+    let connection = connection.transaction()?;
+    let mut tables = index_tables(&connection)?;
+    tables.push("config".to_owned());
+    for table in tables {
+        connection.execute(&format!("DELETE FROM main.{};", table), [])?;
+        connection.execute(
+            &format!("INSERT INTO main.{0} SELECT * FROM snapshot.{0};", table),
+            [],
+        )?;
+    }
+    connection.commit()?;
+    Ok(())
+}
+
+/**
+ * Deletes all indexed data, so that the next sync starts from genesis
+ */
+fn clear_index(connection: &Connection) -> crate::error::Result<()> {
+    for table in index_tables(connection)? {
+        connection.execute(&format!("DELETE FROM {};", table), [])?;
+    }
+    connection.execute("DELETE FROM config WHERE key='BLOCK_COUNT';", [])?;
+    Ok(())
+}
+
+/**
+ * Brings the schema to `SCHEMA_VERSION`, each migration is committed with its version
+ */
+fn migrate(connection: &mut Connection) -> crate::error::Result<()> {
+    let mut version = schema_version(connection)?;
+    if version > SCHEMA_VERSION {
+        return Err(PIVXErrors::IndexTooNew);
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        let transaction = connection.transaction()?;
+        match migration {
+            Migration::Sql(sql) => transaction.execute_batch(sql)?,
+            Migration::Reindex(sql) => {
+                if version > 0 {
+                    println!("Index schema {} is outdated, reindexing", version);
+                }
+                transaction.execute_batch(sql)?;
+                clear_index(&transaction)?;
+            }
+        }
+        version += 1;
+        transaction.execute(
+            "INSERT OR REPLACE INTO config (key, value) VALUES ('SCHEMA_VERSION', ?1);",
+            [version],
+        )?;
+        transaction.commit()?;
+    }
+    Ok(())
 }
 
 impl SqlLite {
     pub async fn new(path: PathBuf) -> crate::error::Result<Self> {
         tauri::async_runtime::spawn_blocking(move || {
-	    let connection = Connection::open(&path)?;
-	    connection.execute_batch("
-BEGIN;
-CREATE TABLE IF NOT EXISTS transactions(txid TEXT NOT NULL, address TEXT NOT NULL, PRIMARY KEY (txid, address));
-CREATE TABLE IF NOT EXISTS vin(txid TEXT NOT NULL, n INTEGER NOT NULL, spender_txid TEXT NOT NULL, PRIMARY KEY (txid, n));
+            let mut writer = open(&path)?;
+            // WAL commits append to the log instead of copying pages to a rollback journal,
+            // and with it NORMAL sync can't corrupt the database on a crash
+            writer.execute_batch(
+                "
+PRAGMA journal_mode=WAL;
+PRAGMA synchronous=NORMAL;
 CREATE TABLE IF NOT EXISTS config(key TEXT NOT NULL PRIMARY KEY, value INTEGER NOT NULL);
-CREATE INDEX IF NOT EXISTS idx_address ON transactions (address);
-COMMIT;
-")?;
-	    Ok(Self{path})
-	}).await?
+",
+            )?;
+            migrate(&mut writer)?;
+            let reader = open(&path)?;
+            Ok(Self {
+                writer: Arc::new(Mutex::new(writer)),
+                reader: Arc::new(Mutex::new(reader)),
+            })
+        })
+        .await?
+    }
+
+    /**
+     * Runs `f` with the reader connection on the blocking thread pool
+     */
+    async fn read<T, F>(&self, f: F) -> crate::error::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> crate::error::Result<T> + Send + 'static,
+    {
+        let reader = self.reader.clone();
+        tauri::async_runtime::spawn_blocking(move || f(&lock(&reader))).await?
     }
 
-    fn connect(&self) -> crate::error::Result<Connection> {
-        Ok(Connection::open(&self.path)?)
+    /**
+     * Runs `f` with the writer connection on the blocking thread pool.
+     * Writes are serialized, so `f` can open a transaction without waiting for sqlite locks
+     */
+    async fn write<T, F>(&self, f: F) -> crate::error::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> crate::error::Result<T> + Send + 'static,
+    {
+        let writer = self.writer.clone();
+        tauri::async_runtime::spawn_blocking(move || f(&mut lock(&writer))).await?
     }
 }
 
 impl Database for SqlLite {
     async fn get_address_txids(&self, address: &str) -> crate::error::Result<Vec<String>> {
-        let connection = self.connect()?;
-        let mut stmt = connection.prepare("SELECT txid FROM transactions WHERE address=?1")?;
-        let mut rows = stmt.query([address])?;
-        let mut txids = vec![];
-        while let Some(row) = rows.next()? {
-            let txid: String = row.get(0)?;
-            txids.push(txid);
-        }
-        Ok(txids)
+        let address = address.to_owned();
+        self.read(move |connection| {
+            let mut stmt = connection.prepare_cached(
+                "SELECT txid FROM transactions WHERE address=?1 GROUP BY txid ORDER BY MIN(rowid);",
+            )?;
+            let mut rows = stmt.query([address])?;
+            let mut txids = vec![];
+            while let Some(row) = rows.next()? {
+                let txid: String = row.get(0)?;
+                txids.push(txid);
+            }
+            Ok(txids)
+        })
+        .await
+    }
+
+    async fn get_delegations(&self, staker: &str) -> crate::error::Result<Vec<Delegation>> {
+        let staker = staker.to_owned();
+        self.read(move |connection| {
+            let mut stmt = connection.prepare_cached(
+                "SELECT d.txid, d.n, d.value, d.staker, d.owner FROM delegations d
+LEFT JOIN vin v ON v.txid=d.txid AND v.n=d.n
+WHERE d.staker=?1 AND v.txid IS NULL;",
+            )?;
+            let delegations = stmt
+                .query_map([staker], |row| {
+                    Ok(Delegation {
+                        txid: row.get(0)?,
+                        n: row.get(1)?,
+                        value: row.get(2)?,
+                        staker: row.get(3)?,
+                        owner: row.get(4)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(delegations)
+        })
+        .await
+    }
+
+    async fn get_delegated_balance(&self, staker: &str) -> crate::error::Result<u64> {
+        let staker = staker.to_owned();
+        self.read(move |connection| {
+            Ok(connection.query_row(
+                "SELECT COALESCE(SUM(d.value), 0) FROM delegations d
+LEFT JOIN vin v ON v.txid=d.txid AND v.n=d.n
+WHERE d.staker=?1 AND v.txid IS NULL;",
+                [staker],
+                |row| row.get(0),
+            )?)
+        })
+        .await
+    }
+
+    async fn get_compact_blocks(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> crate::error::Result<Vec<CompactBlock>> {
+        self.read(move |connection| {
+            let mut stmt = connection.prepare_cached(
+                "SELECT height, hash, time, txs FROM compact_blocks WHERE height BETWEEN ?1 AND ?2 ORDER BY height;",
+            )?;
+            let mut rows = stmt.query([from, to])?;
+            let mut blocks = vec![];
+            while let Some(row) = rows.next()? {
+                let txs: String = row.get(3)?;
+                let txs: Vec<CompactTx> = serde_json::from_str(&txs)?;
+                blocks.push(CompactBlock {
+                    height: row.get(0)?,
+                    hash: row.get(1)?,
+                    time: row.get(2)?,
+                    txs,
+                });
+            }
+            Ok(blocks)
+        })
+        .await
+    }
+
+    async fn store_compact_blocks<I>(&mut self, blocks: I) -> crate::error::Result<()>
+    where
+        I: Iterator<Item = CompactBlock>,
+    {
+        let blocks: Vec<_> = blocks.collect();
+        self.write(move |connection| {
+            let connection = connection.transaction()?;
+            {
+                let mut insert_block = connection.prepare_cached(
+                    "INSERT OR REPLACE INTO compact_blocks (height, hash, time, txs) VALUES (?1, ?2, ?3, ?4);",
+                )?;
+                let mut insert_nullifier = connection.prepare_cached(
+                    "INSERT OR REPLACE INTO nullifiers (nullifier, txid, height) VALUES (?1, ?2, ?3);",
+                )?;
+                for block in blocks {
+                    insert_block.execute(params![
+                        block.height,
+                        block.hash,
+                        block.time,
+                        serde_json::to_string(&block.txs)?
+                    ])?;
+                    for spent in block.get_spent_nullifiers() {
+                        insert_nullifier.execute(params![
+                            spent.nullifier,
+                            spent.txid,
+                            spent.height
+                        ])?;
+                    }
+                }
+            }
+            connection.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_spent_nullifiers(
+        &self,
+        nullifiers: &[&str],
+    ) -> crate::error::Result<Vec<SpentNullifier>> {
+        let nullifiers: Vec<String> = nullifiers.iter().map(|n| n.to_string()).collect();
+        self.read(move |connection| {
+            let mut stmt = connection
+                .prepare_cached("SELECT txid, height FROM nullifiers WHERE nullifier=?1;")?;
+            let mut spent = vec![];
+            for nullifier in nullifiers {
+                let mut rows = stmt.query([&nullifier])?;
+                // There is at most 1 row, since nullifier is primary key
+                if let Some(row) = rows.next()? {
+                    spent.push(SpentNullifier {
+                        nullifier,
+                        txid: row.get(0)?,
+                        height: row.get(1)?,
+                    });
+                }
+            }
+            Ok(spent)
+        })
+        .await
+    }
+
+    async fn get_sapling_tree(&self, height: u64) -> crate::error::Result<Option<SaplingTree>> {
+        self.read(move |connection| {
+            let mut stmt = connection.prepare_cached(
+                "SELECT height, root, tree FROM sapling_trees WHERE height<=?1 ORDER BY height DESC LIMIT 1;",
+            )?;
+            let mut rows = stmt.query([height])?;
+            if let Some(row) = rows.next()? {
+                Ok(Some(SaplingTree {
+                    height: row.get(0)?,
+                    root: row.get(1)?,
+                    tree: row.get(2)?,
+                }))
+            } else {
+                Ok(None)
+            }
+        })
+        .await
+    }
+
+    async fn store_sapling_trees<I>(&mut self, trees: I) -> crate::error::Result<()>
+    where
+        I: Iterator<Item = SaplingTree>,
+    {
+        let trees: Vec<_> = trees.collect();
+        self.write(move |connection| {
+            let connection = connection.transaction()?;
+            {
+                let mut insert_tree = connection.prepare_cached(
+                    "INSERT OR REPLACE INTO sapling_trees (height, root, tree) VALUES (?1, ?2, ?3);",
+                )?;
+                for tree in trees {
+                    insert_tree.execute(params![tree.height, tree.root, tree.tree])?;
+                }
+            }
+            connection.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_header(&self, height: u64) -> crate::error::Result<Option<BlockHeader>> {
+        self.read(move |connection| query_header(connection, "WHERE height=?1", [height]))
+            .await
+    }
+
+    async fn get_header_by_hash(&self, hash: &str) -> crate::error::Result<Option<BlockHeader>> {
+        let hash = hash.to_owned();
+        self.read(move |connection| query_header(connection, "WHERE hash=?1", [hash]))
+            .await
+    }
+
+    async fn get_header_at_time(&self, time: u64) -> crate::error::Result<Option<BlockHeader>> {
+        self.read(move |connection| {
+            query_header(
+                connection,
+                "WHERE time<=?1 ORDER BY time DESC LIMIT 1",
+                [time],
+            )
+        })
+        .await
+    }
+
+    async fn store_headers<I>(&mut self, headers: I) -> crate::error::Result<()>
+    where
+        I: Iterator<Item = BlockHeader>,
+    {
+        let headers: Vec<_> = headers.collect();
+        self.write(move |connection| {
+            let connection = connection.transaction()?;
+            {
+                let mut insert_header = connection.prepare_cached(
+                    "INSERT OR REPLACE INTO headers (height, hash, prev_hash, time, tx_count, size, is_proof_of_stake) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7);",
+                )?;
+                let mut delete_raw_txs = connection
+                    .prepare_cached("DELETE FROM raw_txs WHERE height=?1 AND block_hash!=?2;")?;
+                for header in headers {
+                    insert_header.execute(params![
+                        header.height,
+                        header.hash,
+                        header.prev_hash,
+                        header.time,
+                        header.tx_count,
+                        header.size,
+                        header.is_proof_of_stake
+                    ])?;
+                    delete_raw_txs.execute(params![header.height, header.hash])?;
+                }
+            }
+            connection.commit()?;
+            Ok(())
+        })
+        .await
     }
+
+    async fn get_raw_tx(&self, txid: &str) -> crate::error::Result<Option<RawTx>> {
+        let txid = txid.to_owned();
+        self.read(move |connection| {
+            let mut stmt = connection.prepare_cached(
+                "SELECT hex, block_hash, height, time FROM raw_txs WHERE txid=?1;",
+            )?;
+            let mut rows = stmt.query([&txid])?;
+            if let Some(row) = rows.next()? {
+                Ok(Some(RawTx {
+                    txid,
+                    hex: row.get(0)?,
+                    block_hash: row.get(1)?,
+                    height: row.get(2)?,
+                    time: row.get(3)?,
+                }))
+            } else {
+                Ok(None)
+            }
+        })
+        .await
+    }
+
+    async fn store_raw_tx(&mut self, raw_tx: &RawTx) -> crate::error::Result<()> {
+        let raw_tx = raw_tx.clone();
+        self.write(move |connection| {
+            let mut stmt = connection.prepare_cached(
+                "INSERT OR REPLACE INTO raw_txs (txid, hex, block_hash, height, time) VALUES (?1, ?2, ?3, ?4, ?5);",
+            )?;
+            stmt.execute(params![
+                raw_tx.txid,
+                raw_tx.hex,
+                raw_tx.block_hash,
+                raw_tx.height,
+                raw_tx.time
+            ])?;
+            Ok(())
+        })
+        .await
+    }
+
     async fn store_tx(&mut self, tx: &Tx) -> crate::error::Result<()> {
         self.store_txs(std::iter::once(tx.clone())).await?;
 
@@ -53,178 +547,388 @@ impl Database for SqlLite {
     where
         I: Iterator<Item = Tx>,
     {
-        let mut connection = self.connect()?;
-        let connection = connection.transaction()?;
-        for tx in txs {
-            let txid = &tx.txid;
-            for address in &tx.addresses {
-                connection.execute(
-                    "INSERT OR IGNORE INTO transactions (txid, address) VALUES (?1, ?2);",
-                    params![txid, &address],
+        let txs: Vec<_> = txs.collect();
+        self.write(move |connection| {
+            let connection = connection.transaction()?;
+            {
+                let mut insert_address = connection.prepare_cached(
+                    "INSERT OR IGNORE INTO transactions (txid, address, role, height) VALUES (?1, ?2, ?3, ?4);",
+                )?;
+                // An outpoint is spent once, a different spender means the old one was reorged out
+                let mut insert_vin = connection.prepare_cached(
+                    "INSERT OR REPLACE INTO vin (txid, n, spender_txid, height) VALUES (?1, ?2, ?3, ?4);",
                 )?;
-                for vin in &tx.vin {
-                    connection.execute(
-                        "INSERT OR IGNORE INTO vin (txid, n, spender_txid) VALUES (?1, ?2, ?3)",
-                        params![vin.txid, vin.n, txid],
-                    )?;
+                let mut insert_delegation = connection.prepare_cached(
+                    "INSERT OR IGNORE INTO delegations (txid, n, value, staker, owner, height) VALUES (?1, ?2, ?3, ?4, ?5, ?6);",
+                )?;
+                for tx in txs {
+                    let txid = &tx.txid;
+                    let height = tx.height;
+                    // An address can appear in many outputs of the same tx
+                    let mut addresses: Vec<_> = tx.addresses.iter().collect();
+                    addresses.sort_unstable();
+                    addresses.dedup();
+                    for address in addresses {
+                        insert_address.execute(params![txid, address, "owner", height])?;
+                    }
+                    for vin in &tx.vin {
+                        insert_vin.execute(params![vin.txid, vin.n, txid, height])?;
+                    }
+                    let mut stakers: Vec<_> = tx.delegations.iter().map(|d| &d.staker).collect();
+                    stakers.sort_unstable();
+                    stakers.dedup();
+                    for staker in stakers {
+                        insert_address.execute(params![txid, staker, "staker", height])?;
+                    }
+                    for delegation in &tx.delegations {
+                        insert_delegation.execute(params![
+                            txid,
+                            delegation.n,
+                            delegation.value,
+                            &delegation.staker,
+                            &delegation.owner,
+                            height
+                        ])?;
+                    }
                 }
             }
-        }
-        connection.commit()?;
-        Ok(())
+            connection.commit()?;
+            Ok(())
+        })
+        .await
     }
 
     async fn get_txid_from_vin(&self, vin: &Vin) -> crate::error::Result<Option<String>> {
-        let connection = self.connect()?;
-        let mut stmt =
-            connection.prepare("SELECT spender_txid FROM vin WHERE txid=?1 AND n=?2;")?;
-        let mut rows = stmt.query(params![vin.txid, vin.n])?;
-
-        if let Some(row) = rows.next()? {
-            // There should be at most 1 row, since txid and n are primary key
-            let txid: String = row.get(0)?;
-            Ok(Some(txid))
-        } else {
-            Ok(None)
-        }
+        let vin = vin.clone();
+        self.read(move |connection| {
+            let mut stmt = connection
+                .prepare_cached("SELECT spender_txid FROM vin WHERE txid=?1 AND n=?2;")?;
+            let mut rows = stmt.query(params![vin.txid, vin.n])?;
+
+            if let Some(row) = rows.next()? {
+                // There should be at most 1 row, since txid and n are primary key
+                let txid: String = row.get(0)?;
+                Ok(Some(txid))
+            } else {
+                Ok(None)
+            }
+        })
+        .await
     }
 
     async fn update_block_count(&mut self, block_count: u64) -> crate::error::Result<()> {
-        let connection = self.connect()?;
-        connection.execute(
-            "INSERT OR REPLACE INTO config (key, value) VALUES ('BLOCK_COUNT', ?1);",
-            [block_count],
-        )?;
-        Ok(())
+        self.write(move |connection| {
+            connection.execute(
+                "INSERT INTO config (key, value) VALUES ('BLOCK_COUNT', ?1)
+ON CONFLICT(key) DO UPDATE SET value=MAX(value, excluded.value);",
+                [block_count],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn rewind(&mut self, height: u64) -> crate::error::Result<()> {
+        self.write(move |connection| {
+            let connection = connection.transaction()?;
+            for table in [
+                "headers",
+                "compact_blocks",
+                "nullifiers",
+                "sapling_trees",
+                "raw_txs",
+                "transactions",
+                "vin",
+                "delegations",
+            ] {
+                connection.execute(&format!("DELETE FROM {} WHERE height>?1;", table), [height])?;
+            }
+            connection.execute(
+                "INSERT OR REPLACE INTO config (key, value) VALUES ('BLOCK_COUNT', ?1);",
+                [height],
+            )?;
+            connection.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn wipe(&mut self) -> crate::error::Result<()> {
+        self.write(|connection| {
+            let connection = connection.transaction()?;
+            clear_index(&connection)?;
+            connection.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn backup(&self, path: PathBuf) -> crate::error::Result<()> {
+        // Vacuuming into a file reads a single snapshot, so writes can continue meanwhile
+        self.read(move |connection| {
+            connection.execute("VACUUM INTO ?1;", [path.to_string_lossy()])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn restore(&mut self, path: PathBuf) -> crate::error::Result<()> {
+        self.write(move |connection| {
+            connection.execute("ATTACH DATABASE ?1 AS snapshot;", [path.to_string_lossy()])?;
+            let result = restore_attached(connection);
+            connection.execute("DETACH DATABASE snapshot;", [])?;
+            result
+        })
+        .await
     }
 
     async fn get_last_indexed_block(&self) -> crate::error::Result<u64> {
-        let connection = self.connect()?;
-        let mut stmt = connection.prepare("SELECT value FROM config WHERE key='BLOCK_COUNT';")?;
-        let mut rows = stmt.query([])?;
-
-        if let Some(row) = rows.next()? {
-            Ok(row.get(0)?)
-        } else {
-            Ok(0)
-        }
+        self.read(|connection| {
+            let mut stmt =
+                connection.prepare_cached("SELECT value FROM config WHERE key='BLOCK_COUNT';")?;
+            let mut rows = stmt.query([])?;
+
+            if let Some(row) = rows.next()? {
+                Ok(row.get(0)?)
+            } else {
+                Ok(0)
+            }
+        })
+        .await
     }
 }
 
 #[cfg(test)]
 mod test {
+    use super::super::database::conformance::{
+        conformance_tests, test_address_retrival, FaultInjection,
+    };
     use super::super::types::test::get_test_blocks;
     use super::*;
     use tempdir::TempDir;
 
-    async fn test_address_retrival(sql_lite: &SqlLite) -> crate::error::Result<()> {
-        assert_eq!(
-            sql_lite.get_address_txids("address1").await?,
-            vec!["txid1", "txid2", "txid3"]
-        );
-        assert_eq!(sql_lite.get_address_txids("address2").await?, vec!["txid1"]);
-        assert_eq!(sql_lite.get_address_txids("address4").await?, vec!["txid2"]);
-        assert_eq!(sql_lite.get_address_txids("address5").await?, vec!["txid3"]);
-        assert_eq!(
-            sql_lite.get_address_txids("address6").await?,
-            Vec::<String>::new()
-        );
+    async fn new_sql_lite() -> crate::error::Result<(TempDir, SqlLite)> {
+        let temp_dir = TempDir::new("sqlite-test")?;
+        let sql_lite = SqlLite::new(temp_dir.path().join("test.sqlite")).await?;
+        Ok((temp_dir, sql_lite))
+    }
 
-        assert_eq!(
-            sql_lite
-                .get_txid_from_vin(&Vin {
-                    txid: "spenttxid".to_owned(),
-                    n: 3
-                })
-                .await?,
-            Some("txid1".to_owned())
-        );
+    impl FaultInjection for SqlLite {
+        async fn fail_writes_of(&mut self, txid: &str) -> crate::error::Result<()> {
+            // Aborts the insert of its first row, the transaction is rolled back by sqlite
+            let trigger = format!(
+                "CREATE TEMP TRIGGER fail_writes BEFORE INSERT ON transactions WHEN NEW.txid = '{}' BEGIN SELECT RAISE(ABORT, 'Injected write failure'); END;",
+                txid
+            );
+            self.write(move |connection| Ok(connection.execute_batch(&trigger)?))
+                .await
+        }
+    }
 
-        assert_eq!(
-            sql_lite
-                .get_txid_from_vin(&Vin {
-                    txid: "spenttxid".to_owned(),
-                    n: 1
-                })
-                .await?,
-            Some("txid2".to_owned())
-        );
-        assert_eq!(
-            sql_lite
-                .get_txid_from_vin(&Vin {
-                    txid: "spenttxid2".to_owned(),
-                    n: 5
-                })
-                .await?,
-            Some("txid2".to_owned())
-        );
+    conformance_tests!(new_sql_lite);
 
-        assert_eq!(
-            sql_lite
-                .get_txid_from_vin(&Vin {
-                    txid: "spenttxid2".to_owned(),
-                    n: 0
-                })
-                .await?,
-            None
-        );
+    /// Schema of the indexes created before migrations were introduced
+    const LEGACY_FIXTURE: &str = "
+CREATE TABLE IF NOT EXISTS transactions(txid TEXT NOT NULL, address TEXT NOT NULL, PRIMARY KEY (txid, address));
+CREATE TABLE IF NOT EXISTS vin(txid TEXT NOT NULL, n INTEGER NOT NULL, spender_txid TEXT NOT NULL, PRIMARY KEY (txid, n));
+CREATE TABLE IF NOT EXISTS config(key TEXT NOT NULL PRIMARY KEY, value INTEGER NOT NULL);
+CREATE INDEX IF NOT EXISTS idx_address ON transactions (address);
+INSERT INTO transactions (txid, address) VALUES ('oldtxid', 'address1');
+INSERT INTO config (key, value) VALUES ('BLOCK_COUNT', 500);
+";
 
+    fn fixture(sql: &str) -> crate::error::Result<(TempDir, PathBuf)> {
+        let temp_dir = TempDir::new("sqlite-test")?;
+        let path = temp_dir.path().join("test.sqlite");
+        Connection::open(&path)?.execute_batch(sql)?;
+        Ok((temp_dir, path))
+    }
+
+    async fn assert_reindexed(path: PathBuf) -> crate::error::Result<()> {
+        let mut sql_lite = SqlLite::new(path.clone()).await?;
+        assert_eq!(schema_version(&Connection::open(&path)?)?, SCHEMA_VERSION);
+        assert_eq!(sql_lite.get_last_indexed_block().await?, 0);
         assert_eq!(
-            sql_lite
-                .get_txid_from_vin(&Vin {
-                    txid: "spenttxid5".to_owned(),
-                    n: 3
-                })
-                .await?,
-            None
+            sql_lite.get_address_txids("address1").await?,
+            Vec::<String>::new()
         );
-        Ok(())
+        // The migrated schema must be usable
+        sql_lite
+            .store_txs(
+                get_test_blocks()
+                    .into_iter()
+                    .flat_map(|block| block.txs.into_iter()),
+            )
+            .await?;
+        test_address_retrival(&sql_lite).await
     }
 
     #[tokio::test]
-    async fn test_sqlite() -> crate::error::Result<()> {
-        let temp_dir = TempDir::new("sqlite-test")?;
-        let mut sql_lite = SqlLite::new(temp_dir.path().join("test.sqlite")).await?;
-        let test_blocks = get_test_blocks();
-        for block in test_blocks {
-            for tx in block.txs {
-                sql_lite.store_tx(&tx).await?;
+    async fn it_migrates_legacy_indexes() -> crate::error::Result<()> {
+        let (_temp_dir, path) = fixture(LEGACY_FIXTURE)?;
+        assert_reindexed(path).await
+    }
+
+    #[tokio::test]
+    async fn it_migrates_older_versions() -> crate::error::Result<()> {
+        for version in 1..SCHEMA_VERSION {
+            let mut sql =
+                "CREATE TABLE config(key TEXT NOT NULL PRIMARY KEY, value INTEGER NOT NULL);"
+                    .to_owned();
+            for migration in &MIGRATIONS[..version as usize] {
+                let (Migration::Sql(migration) | Migration::Reindex(migration)) = migration;
+                sql.push_str(migration);
             }
+            sql.push_str(&format!(
+                "
+INSERT INTO transactions (txid, address) VALUES ('oldtxid', 'address1');
+INSERT INTO config (key, value) VALUES ('BLOCK_COUNT', 500);
+INSERT INTO config (key, value) VALUES ('SCHEMA_VERSION', {});
+",
+                version
+            ));
+            let (_temp_dir, path) = fixture(&sql)?;
+            assert_reindexed(path).await?;
         }
-        test_address_retrival(&sql_lite).await?;
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_sqlite_batch() -> crate::error::Result<()> {
-        let temp_dir = TempDir::new("sqlite-test-batch")?;
-        let mut sql_lite = SqlLite::new(temp_dir.path().join("test.sqlite")).await?;
-        let test_blocks = get_test_blocks();
+    async fn it_keeps_up_to_date_indexes() -> crate::error::Result<()> {
+        let (temp_dir, mut sql_lite) = new_sql_lite().await?;
         sql_lite
             .store_txs(
-                test_blocks
+                get_test_blocks()
                     .into_iter()
                     .flat_map(|block| block.txs.into_iter()),
             )
             .await?;
+        sql_lite.update_block_count(500).await?;
+        drop(sql_lite);
+
+        let sql_lite = SqlLite::new(temp_dir.path().join("test.sqlite")).await?;
+        assert_eq!(sql_lite.get_last_indexed_block().await?, 500);
+        test_address_retrival(&sql_lite).await
+    }
 
-        test_address_retrival(&sql_lite).await?;
+    #[tokio::test]
+    async fn it_rejects_newer_indexes() -> crate::error::Result<()> {
+        let (_temp_dir, path) = fixture(&format!(
+            "
+CREATE TABLE config(key TEXT NOT NULL PRIMARY KEY, value INTEGER NOT NULL);
+INSERT INTO config (key, value) VALUES ('SCHEMA_VERSION', {});
+",
+            SCHEMA_VERSION + 1
+        ))?;
+        assert!(matches!(
+            SqlLite::new(path).await,
+            Err(PIVXErrors::IndexTooNew)
+        ));
         Ok(())
     }
 
     #[tokio::test]
-    async fn it_stores_block_count() -> crate::error::Result<()> {
-        let temp_dir = TempDir::new("sqlite-test-batch")?;
-        let mut sql_lite = SqlLite::new(temp_dir.path().join("test.sqlite")).await?;
-        // Last indexed block should be 0 initially
-        assert_eq!(sql_lite.get_last_indexed_block().await?, 0);
-        sql_lite.update_block_count(1345).await?;
-        // Last indexed block should update
-        assert_eq!(sql_lite.get_last_indexed_block().await?, 1345);
-        sql_lite.update_block_count(13450).await?;
-        // Last indexed block should update even if there is already a row
-        assert_eq!(sql_lite.get_last_indexed_block().await?, 13450);
+    #[allow(clippy::await_holding_lock)]
+    async fn it_reads_while_writing() -> crate::error::Result<()> {
+        let (_temp_dir, mut sql_lite) = new_sql_lite().await?;
+        sql_lite.update_block_count(10).await?;
+        // Simulate a long commit by holding the writer
+        let writer = sql_lite.writer.clone();
+        let _writing = lock(&writer);
+        let read = sql_lite.get_last_indexed_block();
+        let block_count = tokio::time::timeout(std::time::Duration::from_secs(5), read)
+            .await
+            .expect("Reads must not wait for writes")?;
+        assert_eq!(block_count, 10);
+        Ok(())
+    }
+
+    /// Two txs per block: one paying a rotating set of addresses,
+    /// and one spending the previous block and sometimes delegating.
+    /// `is_multiple_of` would raise the MSRV, so newer clippies are told to accept `%`
+    #[allow(unknown_lints, clippy::manual_is_multiple_of)]
+    fn synthetic_block(height: u64) -> (BlockHeader, Vec<Tx>) {
+        let hash = |height: u64| format!("{:064x}", height);
+        let txid = |height: u64, i: u64| format!("{:062x}{:02x}", height, i);
+        let header = BlockHeader {
+            height,
+            hash: hash(height),
+            prev_hash: hash(height - 1),
+            time: 1_600_000_000 + height * 60,
+            tx_count: 2,
+            size: 500,
+            is_proof_of_stake: true,
+        };
+        let txs = vec![
+            Tx {
+                txid: txid(height, 0),
+                height: Some(height),
+                vin: vec![],
+                addresses: vec![format!("D{}", height % 1000)],
+                delegations: vec![],
+                shield_spends: vec![],
+                shield_outputs: vec![],
+            },
+            Tx {
+                txid: txid(height, 1),
+                height: Some(height),
+                vin: vec![Vin {
+                    txid: txid(height - 1, 0),
+                    n: 0,
+                }],
+                addresses: vec![format!("D{}", height % 997), format!("D{}", height % 991)],
+                delegations: if height % 100 == 0 {
+                    vec![Delegation {
+                        txid: txid(height, 1),
+                        n: 2,
+                        value: 100,
+                        owner: format!("D{}", height % 997),
+                        staker: format!("S{}", height / 100 % 10),
+                    }]
+                } else {
+                    vec![]
+                },
+                shield_spends: vec![],
+                shield_outputs: vec![],
+            },
+        ];
+        (header, txs)
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_`
+    #[tokio::test]
+    #[ignore]
+    async fn bench_indexes_100k_blocks() -> crate::error::Result<()> {
+        const BLOCKS: u64 = 100_000;
+        const CHUNK: u64 = 1000;
+        let (_temp_dir, mut sql_lite) = new_sql_lite().await?;
+        let start = std::time::Instant::now();
+        for chunk_start in (1..=BLOCKS).step_by(CHUNK as usize) {
+            let (headers, txs): (Vec<_>, Vec<_>) = (chunk_start..chunk_start + CHUNK)
+                .map(synthetic_block)
+                .unzip();
+            sql_lite.store_headers(headers.into_iter()).await?;
+            sql_lite.store_txs(txs.into_iter().flatten()).await?;
+            sql_lite.update_block_count(chunk_start + CHUNK - 1).await?;
+        }
+        let elapsed = start.elapsed();
+        println!(
+            "Indexed {} blocks in {:?} ({:.0} blocks/s)",
+            BLOCKS,
+            elapsed,
+            BLOCKS as f64 / elapsed.as_secs_f64()
+        );
 
+        let start = std::time::Instant::now();
+        let txids = sql_lite.get_address_txids("D0").await?;
+        println!("Address lookup took {:?}", start.elapsed());
+        let expected = (1..=BLOCKS).filter(|h| h % 1000 == 0).count()
+            + (1..=BLOCKS)
+                .filter(|h| h % 997 == 0 || h % 991 == 0)
+                .count();
+        assert_eq!(txids.len(), expected);
+        assert_eq!(sql_lite.get_last_indexed_block().await?, BLOCKS);
+        assert_eq!(sql_lite.get_delegations("S0").await?.len(), 100);
         Ok(())
     }
 }
@@ -1,32 +1,243 @@
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(from = "JsonBlock")]
 pub struct Block {
-    #[serde(rename = "tx")]
+    /// None when the source can't tell the height, e.g. blk files
+    pub height: Option<u64>,
+    pub hash: String,
+    pub prev_hash: String,
+    pub time: u64,
+    pub size: u64,
+    /// Number of txs in the block, `txs` may only contain the relevant ones
+    pub tx_count: u64,
+    pub is_proof_of_stake: bool,
     pub txs: Vec<Tx>,
 }
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub height: u64,
+    pub hash: String,
+    pub prev_hash: String,
+    pub time: u64,
+    pub tx_count: u64,
+    pub size: u64,
+    pub is_proof_of_stake: bool,
+}
 #[derive(Deserialize, Debug, Clone)]
+#[serde(from = "JsonTx")]
 pub struct Tx {
     pub txid: String,
-    #[serde(deserialize_with = "skip_invalid")]
+    /// Height of the block that mined it, None when the source can't tell
+    pub height: Option<u64>,
     pub vin: Vec<Vin>,
-
-    #[serde(deserialize_with = "concat_addresses")]
-    #[serde(rename = "vout")]
     pub addresses: Vec<String>,
+    pub delegations: Vec<Delegation>,
+    /// Nullifiers of the sapling spends, hex encoded in serialization order
+    pub shield_spends: Vec<String>,
+    pub shield_outputs: Vec<ShieldOutput>,
+}
+
+/// The part of a sapling output needed to trial decrypt it.
+/// All fields are hex encoded in serialization order
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ShieldOutput {
+    pub cmu: String,
+    pub epk: String,
+    /// First 52 bytes of the encrypted note
+    pub ciphertext: String,
+}
+
+/// Shield data of a block, in the same spirit as lightwalletd's compact blocks
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CompactBlock {
+    pub height: u64,
+    pub hash: String,
+    pub time: u64,
+    pub txs: Vec<CompactTx>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CompactTx {
+    pub txid: String,
+    pub spends: Vec<String>,
+    pub outputs: Vec<ShieldOutput>,
+}
+
+/// Where a sapling nullifier has been revealed, i.e. where its note was spent
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SpentNullifier {
+    pub nullifier: String,
+    pub txid: String,
+    pub height: u64,
+}
+
+/// Snapshot of the sapling note commitment tree after the block at `height`.
+/// `root` and `tree` are hex encoded in serialization order,
+/// `tree` being the frontier as serialized by pivxd
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SaplingTree {
+    pub height: u64,
+    pub root: String,
+    pub tree: String,
+}
+
+/// A confirmed raw transaction, with the block it was mined in
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RawTx {
+    pub txid: String,
+    pub hex: String,
+    pub block_hash: String,
+    pub height: u64,
+    pub time: u64,
+}
+
+/// A difference between the index and a block of the block source
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum IndexMismatch {
+    MissingHeader {
+        height: u64,
+    },
+    /// The indexed block has been reorged out
+    WrongHeader {
+        height: u64,
+        expected_hash: String,
+        indexed_hash: String,
+    },
+    MissingAddressTx {
+        height: u64,
+        address: String,
+        txid: String,
+    },
+    WrongSpender {
+        height: u64,
+        vin: Vin,
+        expected_txid: String,
+        indexed_txid: Option<String>,
+    },
+}
+
+impl IndexMismatch {
+    pub fn height(&self) -> u64 {
+        match self {
+            Self::MissingHeader { height }
+            | Self::WrongHeader { height, .. }
+            | Self::MissingAddressTx { height, .. }
+            | Self::WrongSpender { height, .. } => *height,
+        }
+    }
+}
+
+/// Result of checking the index against the block source
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub checked_blocks: u64,
+    pub mismatches: Vec<IndexMismatch>,
+    /// The range of heights that was indexed again, if repairing was requested
+    pub repaired: Option<(u64, u64)>,
+}
+
+/// Describes the index stored in a snapshot file
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotManifest {
+    /// Height of the last indexed block
+    pub height: u64,
+    pub block_hash: String,
+    /// Hex encoded sha256 of the database backup
+    pub sha256: String,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncState {
+    /// Not syncing, or synced to the tip and waiting for new blocks
+    #[default]
+    Idle,
+    Syncing,
+    /// The index was wiped, queries return partial results until the sync reaches the tip
+    Rebuilding,
+    Cancelled,
+    Failed,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SyncStatus {
+    pub state: SyncState,
+    pub block_count: u64,
+}
+
+pub const COMPACT_CIPHERTEXT_SIZE: usize = 52;
+
+/// A cold stake output, with the staker in its S-address form
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Delegation {
+    pub txid: String,
+    pub n: u32,
+    pub value: u64,
+    pub staker: String,
+    pub owner: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonBlock {
+    height: Option<u64>,
+    #[serde(default)]
+    hash: String,
+    #[serde(default, rename = "previousblockhash")]
+    prev_hash: String,
+    #[serde(default)]
+    time: u64,
+    #[serde(default)]
+    size: u64,
+    tx: Vec<JsonTx>,
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonTx {
+    txid: String,
+    #[serde(deserialize_with = "skip_invalid")]
+    vin: Vec<Vin>,
+    vout: Vec<Vout>,
+    #[serde(default, rename = "vShieldSpend")]
+    shield_spends: Vec<JsonShieldSpend>,
+    #[serde(default, rename = "vShieldOutput")]
+    shield_outputs: Vec<JsonShieldOutput>,
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonShieldSpend {
+    nullifier: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct JsonShieldOutput {
+    cmu: String,
+    ephemeral_key: String,
+    enc_ciphertext: String,
 }
 
 #[derive(Deserialize, Debug)]
 struct Vout {
+    #[serde(default)]
+    pub value: f64,
+    #[serde(default)]
+    pub n: u32,
     #[serde(rename = "scriptPubKey")]
     pub script_pub_key: Option<ScriptPubKey>,
 }
 #[derive(Deserialize, Debug)]
 struct ScriptPubKey {
+    #[serde(rename = "type")]
+    pub script_type: Option<String>,
     pub addresses: Option<Vec<String>>,
+    #[serde(default)]
+    pub hex: String,
 }
 
-#[derive(Deserialize, Debug, Clone, Eq, Hash, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, Hash, PartialEq)]
 pub struct Vin {
     #[serde(default)]
     pub txid: String,
@@ -34,18 +245,156 @@ pub struct Vin {
     pub n: u32,
 }
 
-fn concat_addresses<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let vouts: Vec<Vout> = Vec::deserialize(deserializer)?;
-    let mut addresses: Vec<String> = vec![];
-    for vout in vouts {
-        if let Some(vout_addresses) = vout.script_pub_key.and_then(|s| s.addresses) {
-            addresses.extend(vout_addresses);
+impl From<JsonBlock> for Block {
+    fn from(block: JsonBlock) -> Self {
+        // The coinstake is the second tx, and its first output is empty
+        let is_proof_of_stake = block.tx.get(1).is_some_and(|tx| {
+            tx.vout.first().is_some_and(
+                |vout| !matches!(&vout.script_pub_key, Some(script) if !script.hex.is_empty()),
+            )
+        });
+        Self {
+            height: block.height,
+            hash: block.hash,
+            prev_hash: block.prev_hash,
+            time: block.time,
+            size: block.size,
+            tx_count: block.tx.len() as u64,
+            is_proof_of_stake,
+            txs: block
+                .tx
+                .into_iter()
+                .map(|tx| Tx {
+                    height: block.height,
+                    ..Tx::from(tx)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<JsonTx> for Tx {
+    fn from(tx: JsonTx) -> Self {
+        let mut addresses: Vec<String> = vec![];
+        let mut delegations = vec![];
+        for vout in tx.vout {
+            let Some(script_pub_key) = vout.script_pub_key else {
+                continue;
+            };
+            let vout_addresses = script_pub_key.addresses.unwrap_or_default();
+            match (
+                script_pub_key.script_type.as_deref(),
+                vout_addresses.as_slice(),
+            ) {
+                // pivxd lists the staker first and the owner second
+                (Some("coldstake"), [staker, owner]) => {
+                    addresses.push(owner.clone());
+                    delegations.push(Delegation {
+                        txid: tx.txid.clone(),
+                        n: vout.n,
+                        value: (vout.value * 1e8).round() as u64,
+                        staker: staker.clone(),
+                        owner: owner.clone(),
+                    });
+                }
+                _ => addresses.extend(vout_addresses),
+            }
         }
+        Tx {
+            txid: tx.txid,
+            height: None,
+            vin: tx.vin,
+            addresses,
+            delegations,
+            shield_spends: tx
+                .shield_spends
+                .into_iter()
+                .map(|spend| reverse_hex(&spend.nullifier))
+                .collect(),
+            shield_outputs: tx
+                .shield_outputs
+                .into_iter()
+                .map(|output| ShieldOutput {
+                    cmu: reverse_hex(&output.cmu),
+                    epk: reverse_hex(&output.ephemeral_key),
+                    ciphertext: output
+                        .enc_ciphertext
+                        .chars()
+                        .take(COMPACT_CIPHERTEXT_SIZE * 2)
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// pivxd displays uint256 fields in reverse byte order
+fn reverse_hex(hex: &str) -> String {
+    let mut bytes = hex::decode(hex).unwrap_or_default();
+    bytes.reverse();
+    hex::encode(bytes)
+}
+
+impl Tx {
+    pub fn has_shield_data(&self) -> bool {
+        !self.shield_spends.is_empty() || !self.shield_outputs.is_empty()
+    }
+}
+
+impl Block {
+    /**
+     * Returns None if the height is unknown
+     */
+    pub fn to_header(&self) -> Option<BlockHeader> {
+        Some(BlockHeader {
+            height: self.height?,
+            hash: self.hash.clone(),
+            prev_hash: self.prev_hash.clone(),
+            time: self.time,
+            tx_count: self.tx_count,
+            size: self.size,
+            is_proof_of_stake: self.is_proof_of_stake,
+        })
+    }
+
+    /**
+     * Returns the shield data of this block.
+     * Returns None if the height is unknown or if there are no shield transactions
+     */
+    pub fn to_compact(&self) -> Option<CompactBlock> {
+        let height = self.height?;
+        let txs: Vec<_> = self
+            .txs
+            .iter()
+            .filter(|tx| tx.has_shield_data())
+            .map(|tx| CompactTx {
+                txid: tx.txid.clone(),
+                spends: tx.shield_spends.clone(),
+                outputs: tx.shield_outputs.clone(),
+            })
+            .collect();
+        if txs.is_empty() {
+            return None;
+        }
+        Some(CompactBlock {
+            height,
+            hash: self.hash.clone(),
+            time: self.time,
+            txs,
+        })
+    }
+}
+
+impl CompactBlock {
+    pub fn get_spent_nullifiers(&self) -> impl Iterator<Item = SpentNullifier> + '_ {
+        self.txs.iter().flat_map(move |tx| {
+            tx.spends.iter().map(move |nullifier| SpentNullifier {
+                nullifier: nullifier.clone(),
+                txid: tx.txid.clone(),
+                height: self.height,
+            })
+        })
     }
-    Ok(addresses)
 }
 
 fn skip_invalid<'de, D>(deserializer: D) -> Result<Vec<Vin>, D::Error>
@@ -66,18 +415,43 @@ pub mod test {
     pub fn get_test_blocks() -> Vec<Block> {
         vec![
             Block {
+                height: Some(1),
+                hash: "hash1".to_owned(),
+                prev_hash: "hash0".to_owned(),
+                time: 1000,
+                size: 100,
+                tx_count: 1,
+                is_proof_of_stake: false,
                 txs: vec![Tx {
                     txid: "txid1".to_owned(),
+                    height: Some(1),
                     addresses: vec!["address1".to_owned(), "address2".to_owned()],
                     vin: vec![Vin {
                         txid: "spenttxid".to_owned(),
                         n: 3,
                     }],
+                    delegations: vec![Delegation {
+                        txid: "txid1".to_owned(),
+                        n: 1,
+                        value: 100,
+                        staker: "staker1".to_owned(),
+                        owner: "address2".to_owned(),
+                    }],
+                    shield_spends: vec![],
+                    shield_outputs: vec![],
                 }],
             },
             Block {
+                height: Some(2),
+                hash: "hash2".to_owned(),
+                prev_hash: "hash1".to_owned(),
+                time: 1060,
+                size: 200,
+                tx_count: 1,
+                is_proof_of_stake: false,
                 txs: vec![Tx {
                     txid: "txid2".to_owned(),
+                    height: Some(2),
                     addresses: vec!["address1".to_owned(), "address4".to_owned()],
                     vin: vec![
                         Vin {
@@ -88,14 +462,51 @@ pub mod test {
                             txid: "spenttxid2".to_owned(),
                             n: 5,
                         },
+                        Vin {
+                            txid: "txid1".to_owned(),
+                            n: 1,
+                        },
                     ],
+                    delegations: vec![],
+                    shield_spends: vec!["nullifier1".to_owned()],
+                    shield_outputs: vec![ShieldOutput {
+                        cmu: "01".repeat(32),
+                        epk: "epk1".to_owned(),
+                        ciphertext: "ciphertext1".to_owned(),
+                    }],
                 }],
             },
             Block {
+                height: Some(3),
+                hash: "hash3".to_owned(),
+                prev_hash: "hash2".to_owned(),
+                time: 1120,
+                size: 300,
+                tx_count: 1,
+                is_proof_of_stake: false,
                 txs: vec![Tx {
                     txid: "txid3".to_owned(),
+                    height: Some(3),
                     addresses: vec!["address1".to_owned(), "address5".to_owned()],
                     vin: vec![],
+                    delegations: vec![
+                        Delegation {
+                            txid: "txid3".to_owned(),
+                            n: 0,
+                            value: 250,
+                            staker: "staker1".to_owned(),
+                            owner: "address5".to_owned(),
+                        },
+                        Delegation {
+                            txid: "txid3".to_owned(),
+                            n: 1,
+                            value: 50,
+                            staker: "staker2".to_owned(),
+                            owner: "address1".to_owned(),
+                        },
+                    ],
+                    shield_spends: vec![],
+                    shield_outputs: vec![],
                 }],
             },
         ]
@@ -106,6 +517,11 @@ pub mod test {
         let block: Block = serde_json::from_str(
             r#"
 {
+    "height": 12,
+    "hash": "blockhash",
+    "previousblockhash": "prevhash",
+    "time": 1700000000,
+    "size": 1234,
     "tx": [
         {
             "txid": "123",
@@ -150,6 +566,26 @@ pub mod test {
                     "scriptPubKey": {
                         "addresses": ["Address4", "Address5"]
                     }
+                },
+                {
+                    "value": 12.5,
+                    "n": 3,
+                    "scriptPubKey": {
+                        "type": "coldstake",
+                        "addresses": ["Staker1", "Address6"]
+                    }
+                }
+            ],
+            "vShieldSpend": [
+                {
+                    "nullifier": "0102"
+                }
+            ],
+            "vShieldOutput": [
+                {
+                    "cmu": "0a0b",
+                    "ephemeralKey": "0c0d",
+                    "encCiphertext": "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff0011223344556677"
                 }
             ]
         }
@@ -176,9 +612,59 @@ pub mod test {
         );
         assert_eq!(
             block.txs[1].addresses,
-            vec!["Address3", "Address4", "Address5"]
+            vec!["Address3", "Address4", "Address5", "Address6"]
         );
         assert_eq!(block.txs[1].vin, vec![]);
+        assert_eq!(block.txs[0].delegations, vec![]);
+        assert_eq!(block.height, Some(12));
+        assert_eq!(block.hash, "blockhash");
+        assert_eq!(block.time, 1700000000);
+        assert_eq!(
+            block.to_header(),
+            Some(BlockHeader {
+                height: 12,
+                hash: "blockhash".to_owned(),
+                prev_hash: "prevhash".to_owned(),
+                time: 1700000000,
+                tx_count: 2,
+                size: 1234,
+                // The first output of the second tx is empty
+                is_proof_of_stake: true,
+            })
+        );
+        assert!(!block.txs[0].has_shield_data());
+        assert_eq!(block.txs[1].shield_spends, vec!["0201"]);
+        assert_eq!(
+            block.txs[1].shield_outputs,
+            vec![ShieldOutput {
+                cmu: "0b0a".to_owned(),
+                epk: "0d0c".to_owned(),
+                ciphertext: "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff00112233".to_owned(),
+            }]
+        );
+        assert_eq!(
+            block.to_compact(),
+            Some(CompactBlock {
+                height: 12,
+                hash: "blockhash".to_owned(),
+                time: 1700000000,
+                txs: vec![CompactTx {
+                    txid: "456".to_owned(),
+                    spends: block.txs[1].shield_spends.clone(),
+                    outputs: block.txs[1].shield_outputs.clone(),
+                }]
+            })
+        );
+        assert_eq!(
+            block.txs[1].delegations,
+            vec![Delegation {
+                txid: "456".to_owned(),
+                n: 3,
+                value: 1_250_000_000,
+                staker: "Staker1".to_owned(),
+                owner: "Address6".to_owned(),
+            }]
+        );
         Ok(())
     }
 }
@@ -0,0 +1,337 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+use super::Binary;
+use global_function_macro::generate_global_functions;
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Child;
+use tokio::sync::mpsc;
+
+/// Emitted with a `LogLine` payload for every line logged by pivxd
+pub const NODE_LOG_EVENT: &str = "node-log";
+/// Name of the log file in the data dir
+const LOG_FILE: &str = "pivxd.log";
+/// Lines kept in memory for `get_logs`
+const MAX_LOG_LINES: usize = 1000;
+/// The log file is rotated once it reaches this size
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+/// Rotated log files kept besides the current one, as pivxd.log.1, pivxd.log.2...
+const ROTATED_LOG_FILES: usize = 2;
+/// Stdout lines waiting to be read by `wait_for_load`. Lines are dropped when it's full
+const STDOUT_CHANNEL_SIZE: usize = 1024;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogLine {
+    pub stream: LogStream,
+    pub line: String,
+}
+
+/// Log file that's rotated once it grows over `max_size`
+struct LogFile {
+    path: PathBuf,
+    max_size: u64,
+    file: Option<File>,
+    size: u64,
+}
+
+impl LogFile {
+    fn new(path: PathBuf, max_size: u64) -> Self {
+        Self {
+            path,
+            max_size,
+            file: None,
+            size: 0,
+        }
+    }
+
+    /**
+     * Returns the path of the `index`th rotated file, 0 being the current one
+     */
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        match index {
+            0 => self.path.clone(),
+            _ => PathBuf::from(format!("{}.{}", self.path.display(), index)),
+        }
+    }
+
+    /**
+     * Shifts every log file by one, dropping the oldest
+     */
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file = None;
+        for index in (0..ROTATED_LOG_FILES).rev() {
+            let path = self.rotated_path(index);
+            if path.exists() {
+                std::fs::rename(path, self.rotated_path(index + 1))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        if self.file.is_some() && self.size + line.len() as u64 + 1 > self.max_size {
+            self.rotate()?;
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?;
+                self.size = file.metadata()?.len();
+                self.file.insert(file)
+            }
+        };
+        writeln!(file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+/// Output of pivxd, kept in memory and in a rotating log file in the data dir
+pub struct Node {
+    lines: Mutex<VecDeque<LogLine>>,
+    log_file: Mutex<LogFile>,
+}
+
+static NODE: OnceLock<Node> = OnceLock::new();
+
+fn node() -> &'static Node {
+    NODE.get_or_init(|| {
+        let data_dir = Binary::get_data_dir().expect("No data dir");
+        Node::new(data_dir.join(LOG_FILE), MAX_LOG_SIZE)
+    })
+}
+
+pub async fn get_node() -> &'static Node {
+    node()
+}
+
+/**
+ * Logs the stdout and stderr of `handle` until they end, they must be piped.
+ * Returns the stdout lines for `wait_for_load`, they stop being sent once the receiver is dropped
+ */
+pub fn capture(handle: &mut Child) -> Option<mpsc::Receiver<String>> {
+    let node = node();
+    if let Some(stderr) = handle.stderr.take() {
+        tokio::spawn(node.read_lines(stderr, LogStream::Stderr, None));
+    }
+    let stdout = handle.stdout.take()?;
+    let (sender, receiver) = mpsc::channel(STDOUT_CHANNEL_SIZE);
+    tokio::spawn(node.read_lines(stdout, LogStream::Stdout, Some(sender)));
+    Some(receiver)
+}
+
+impl Node {
+    fn new(log_path: PathBuf, max_log_size: u64) -> Self {
+        Self {
+            lines: Mutex::new(VecDeque::with_capacity(MAX_LOG_LINES)),
+            log_file: Mutex::new(LogFile::new(log_path, max_log_size)),
+        }
+    }
+
+    /**
+     * Keeps `line` in memory, writes it to the log file and emits it to the UI
+     */
+    fn push(&self, line: LogLine) {
+        let file_line = match line.stream {
+            LogStream::Stdout => line.line.clone(),
+            LogStream::Stderr => format!("[stderr] {}", line.line),
+        };
+        if let Err(err) = self.log_file.lock().unwrap().write_line(&file_line) {
+            eprintln!("Warning: failed to write pivxd log: {}", err);
+        }
+        {
+            let mut lines = self.lines.lock().unwrap();
+            if lines.len() == MAX_LOG_LINES {
+                lines.pop_front();
+            }
+            lines.push_back(line.clone());
+        }
+        crate::events::emit(NODE_LOG_EVENT, line);
+    }
+
+    /**
+     * Logs every line of `stream` until it ends, sending them to `tee` as well while it's open
+     */
+    async fn read_lines<R: AsyncRead + Unpin>(
+        &self,
+        stream: R,
+        log_stream: LogStream,
+        mut tee: Option<mpsc::Sender<String>>,
+    ) {
+        let mut reader = BufReader::new(stream);
+        let mut buf = vec![];
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf).await {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(err) => {
+                    eprintln!("Warning: failed to read pivxd output: {}", err);
+                    break;
+                }
+            }
+            let line = String::from_utf8_lossy(&buf).trim_end().to_string();
+            if let Some(sender) = &tee {
+                if let Err(mpsc::error::TrySendError::Closed(_)) = sender.try_send(line.clone()) {
+                    tee = None;
+                }
+            }
+            self.push(LogLine {
+                stream: log_stream,
+                line,
+            });
+        }
+    }
+}
+
+#[generate_global_functions]
+impl Node {
+    /// Returns the last `lines` lines logged by pivxd that contain `filter`, ignoring case.
+    /// Older lines are in the log file
+    pub async fn get_logs(
+        &self,
+        lines: usize,
+        filter: Option<String>,
+    ) -> crate::error::Result<Vec<LogLine>> {
+        let filter = filter.map(|filter| filter.to_lowercase());
+        let logs = self.lines.lock().unwrap();
+        let mut matching = logs
+            .iter()
+            .rev()
+            .filter(|log| match &filter {
+                Some(filter) => log.line.to_lowercase().contains(filter),
+                None => true,
+            })
+            .take(lines)
+            .cloned()
+            .collect::<Vec<_>>();
+        matching.reverse();
+        Ok(matching)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn lines(count: usize) -> String {
+        (0..count).map(|i| format!("line {}\n", i)).collect()
+    }
+
+    #[tokio::test]
+    async fn keeps_the_last_lines_in_memory() -> crate::error::Result<()> {
+        let temp_dir = TempDir::new("pivxd-logs")?;
+        let node = Node::new(temp_dir.path().join(LOG_FILE), MAX_LOG_SIZE);
+        node.read_lines(lines(MAX_LOG_LINES + 5).as_bytes(), LogStream::Stdout, None)
+            .await;
+        node.read_lines(&b"Error: disk full\r\n"[..], LogStream::Stderr, None)
+            .await;
+
+        let logs = node.get_logs(usize::MAX, None).await?;
+        assert_eq!(logs.len(), MAX_LOG_LINES);
+        assert_eq!(logs[0].line, "line 6");
+        assert_eq!(
+            node.get_logs(2, None).await?,
+            vec![
+                LogLine {
+                    stream: LogStream::Stdout,
+                    line: format!("line {}", MAX_LOG_LINES + 4),
+                },
+                LogLine {
+                    stream: LogStream::Stderr,
+                    line: "Error: disk full".to_string(),
+                },
+            ]
+        );
+        let errors = node.get_logs(10, Some("ERROR".to_string())).await?;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].stream, LogStream::Stderr);
+        let matching = node.get_logs(2, Some("line 99".to_string())).await?;
+        let matching = matching.iter().map(|l| l.line.as_str()).collect::<Vec<_>>();
+        assert_eq!(matching, vec!["line 998", "line 999"]);
+
+        // Every line is in the log file
+        let content = std::fs::read_to_string(temp_dir.path().join(LOG_FILE))?;
+        assert!(content.starts_with("line 0\nline 1\n"));
+        assert!(content.ends_with("[stderr] Error: disk full\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sends_lines_until_the_receiver_is_dropped() -> crate::error::Result<()> {
+        let temp_dir = TempDir::new("pivxd-logs")?;
+        let node = Node::new(temp_dir.path().join(LOG_FILE), MAX_LOG_SIZE);
+        let (sender, mut receiver) = mpsc::channel(10);
+        node.read_lines(lines(2).as_bytes(), LogStream::Stdout, Some(sender))
+            .await;
+        assert_eq!(receiver.recv().await.as_deref(), Some("line 0"));
+        assert_eq!(receiver.recv().await.as_deref(), Some("line 1"));
+        // The stream ended, so the sender was dropped
+        assert_eq!(receiver.recv().await, None);
+
+        let (sender, receiver) = mpsc::channel(10);
+        drop(receiver);
+        node.read_lines(lines(2).as_bytes(), LogStream::Stdout, Some(sender))
+            .await;
+        assert_eq!(node.get_logs(usize::MAX, None).await?.len(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rotates_the_log_file() -> crate::error::Result<()> {
+        let temp_dir = TempDir::new("pivxd-logs")?;
+        let log_path = temp_dir.path().join(LOG_FILE);
+        // Fits two lines of 7 bytes
+        let node = Node::new(log_path.clone(), 14);
+        node.read_lines(lines(7).as_bytes(), LogStream::Stdout, None)
+            .await;
+
+        let read = |name: &str| std::fs::read_to_string(temp_dir.path().join(name));
+        assert_eq!(read(LOG_FILE)?, "line 6\n");
+        assert_eq!(read("pivxd.log.1")?, "line 4\nline 5\n");
+        assert_eq!(read("pivxd.log.2")?, "line 2\nline 3\n");
+        assert!(!temp_dir.path().join("pivxd.log.3").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn captures_the_output_of_processes() -> crate::error::Result<()> {
+        let mut handle = tokio::process::Command::new("sh")
+            .args(["-c", "echo loaded; echo failed >&2"])
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+        let mut stdout = capture(&mut handle).expect("stdout is piped");
+        assert_eq!(stdout.recv().await.as_deref(), Some("loaded"));
+        assert_eq!(stdout.recv().await, None);
+        handle.wait().await?;
+
+        let node = get_node().await;
+        let stderr = LogLine {
+            stream: LogStream::Stderr,
+            line: "failed".to_string(),
+        };
+        for _ in 0..100 {
+            if node.get_logs(usize::MAX, None).await?.contains(&stderr) {
+                return Ok(());
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("stderr wasn't captured");
+    }
+}
@@ -1,12 +1,32 @@
+pub mod logs;
 #[cfg(test)]
 mod test;
 
 use crate::error::PIVXErrors;
-use std::fs::File;
+use reqwest::{header, StatusCode};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use tokio::process::{Child, Command};
+use tokio::sync::mpsc;
+
+/// Emitted with a `DownloadProgress` payload while a file is downloaded
+pub const DOWNLOAD_PROGRESS_EVENT: &str = "download-progress";
+/// Progress is reported every this many bytes
+const PROGRESS_STEP: u64 = 1024 * 1024;
+/// A download is resumed this many times after network errors before giving up
+const MAX_DOWNLOAD_ATTEMPTS: u32 = 5;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DownloadProgress {
+    pub file_name: String,
+    /// Bytes on disk, including the ones downloaded before resuming
+    pub downloaded: u64,
+    /// Size of the archive, if the server sent it
+    pub total: Option<u64>,
+}
 
 pub trait BinaryDefinition {
     fn get_url(&self) -> &str;
@@ -15,11 +35,23 @@ pub trait BinaryDefinition {
     fn decompress_archive(&self, dir: &Path) -> Result<(), PIVXErrors>;
     fn get_binary_path(&self, base_dir: &Path) -> PathBuf;
     fn get_binary_args(&self, base_dir: &Path) -> Result<Vec<String>, PIVXErrors>;
-    async fn wait_for_load(&self, handle: &mut Child) -> crate::error::Result<()>;
+    /**
+     * Resolves once the binary is loaded. `stdout` receives its output lines, if it has any
+     */
+    async fn wait_for_load(
+        &self,
+        stdout: Option<mpsc::Receiver<String>>,
+    ) -> crate::error::Result<()>;
+    /**
+     * Checks the downloaded archive against the signature of its publisher,
+     * it's called after the sha256 check and before decompressing
+     */
+    async fn verify_archive(&self, archive: &Path) -> crate::error::Result<()>;
 }
 
 pub struct Binary {
     handle: Child,
+    stdout: Option<mpsc::Receiver<String>>,
 }
 
 impl Drop for Binary {
@@ -32,30 +64,137 @@ impl Drop for Binary {
 
 impl Binary {
     /**
-     * Fetches a binary and copies it into $XDG_DATA_HOME/pivx-rust or equivalent based on OS
+     * Fetches a binary and copies it into $XDG_DATA_HOME/pivx-rust or equivalent based on OS.
+     * Progress is emitted to the UI as `DOWNLOAD_PROGRESS_EVENT`
      */
     async fn fetch<T: BinaryDefinition + Send>(
         dir: &Path,
         binary_definition: &T,
     ) -> Result<(), PIVXErrors> {
-        let mut request = reqwest::get(binary_definition.get_url()).await?;
-        if !request.status().is_success() {
-            return Err(PIVXErrors::ServerError);
-        }
+        Self::fetch_with_progress(dir, binary_definition, |progress| {
+            crate::events::emit(DOWNLOAD_PROGRESS_EVENT, progress)
+        })
+        .await
+    }
+
+    /**
+     * Fetches a binary, resuming a partially downloaded archive if there is one.
+     * The archive is removed if it fails verification, so that the next fetch starts over
+     */
+    async fn fetch_with_progress<T: BinaryDefinition + Send>(
+        dir: &Path,
+        binary_definition: &T,
+        on_progress: impl FnMut(DownloadProgress),
+    ) -> Result<(), PIVXErrors> {
         std::fs::create_dir_all(dir)?;
         let file_path = dir.join(binary_definition.get_archive_name());
-        let mut file = File::create(&file_path)?;
-        while let Some(chunk) = request.chunk().await? {
-            file.write_all(&chunk)?;
+        Self::download_verified(
+            binary_definition.get_url(),
+            &file_path,
+            binary_definition.get_sha256sum(),
+            on_progress,
+        )
+        .await?;
+        let result = binary_definition.verify_archive(&file_path).await;
+        if result.is_err() {
+            let _ = std::fs::remove_file(&file_path);
         }
+        result
+    }
 
-        let digest =
-            sha256::try_digest(&file_path).map_err(|e| PIVXErrors::WrongSha256Sum(Some(e)))?;
-        if digest != binary_definition.get_sha256sum() {
-            Err(PIVXErrors::WrongSha256Sum(None))
-        } else {
-            Ok(())
+    /**
+     * Downloads `url` to `file_path`, resuming a partial file if there is one,
+     * and checks it against `sha256`. The file is removed if it doesn't match
+     */
+    pub async fn download_verified(
+        url: &str,
+        file_path: &Path,
+        sha256: &str,
+        mut on_progress: impl FnMut(DownloadProgress),
+    ) -> Result<(), PIVXErrors> {
+        let mut attempts = 1;
+        loop {
+            match Self::download(url, file_path, &mut on_progress).await {
+                Ok(()) => break,
+                Err(PIVXErrors::FetchError(err)) if attempts < MAX_DOWNLOAD_ATTEMPTS => {
+                    eprintln!("Warning: download interrupted, resuming: {}", err);
+                    attempts += 1;
+                }
+                Err(err) => return Err(err),
+            }
         }
+
+        let result = match sha256::try_digest(file_path) {
+            Ok(digest) if digest == sha256 => Ok(()),
+            Ok(_) => Err(PIVXErrors::WrongSha256Sum(None)),
+            Err(err) => Err(PIVXErrors::WrongSha256Sum(Some(err))),
+        };
+        if result.is_err() {
+            let _ = std::fs::remove_file(file_path);
+        }
+        result
+    }
+
+    /**
+     * Downloads `url` to `file_path`, continuing from its current length
+     * with a range request if the file exists
+     */
+    async fn download(
+        url: &str,
+        file_path: &Path,
+        on_progress: &mut impl FnMut(DownloadProgress),
+    ) -> Result<(), PIVXErrors> {
+        let mut downloaded = file_path.metadata().map(|m| m.len()).unwrap_or(0);
+        let mut request = reqwest::Client::new().get(url);
+        if downloaded > 0 {
+            request = request.header(header::RANGE, format!("bytes={}-", downloaded));
+        }
+        let mut response = request.send().await?;
+        let mut file = match response.status() {
+            StatusCode::PARTIAL_CONTENT => {
+                let range_start = response
+                    .headers()
+                    .get(header::CONTENT_RANGE)
+                    .and_then(|range| range.to_str().ok())
+                    .and_then(|range| range.strip_prefix("bytes "))
+                    .and_then(|range| range.split('-').next())
+                    .and_then(|start| start.parse::<u64>().ok());
+                if range_start != Some(downloaded) {
+                    return Err(PIVXErrors::ServerError);
+                }
+                OpenOptions::new().append(true).open(file_path)?
+            }
+            // The file was already complete, the checksum tells whether it's valid
+            StatusCode::RANGE_NOT_SATISFIABLE if downloaded > 0 => return Ok(()),
+            status if status.is_success() => {
+                // The server ignored the range, so the file is downloaded again
+                downloaded = 0;
+                File::create(file_path)?
+            }
+            _ => return Err(PIVXErrors::ServerError),
+        };
+
+        let total = response.content_length().map(|length| length + downloaded);
+        let file_name = file_path.file_name().unwrap_or_default().to_string_lossy();
+        let progress = |downloaded| DownloadProgress {
+            file_name: file_name.to_string(),
+            downloaded,
+            total,
+        };
+        let mut reported = downloaded;
+        on_progress(progress(downloaded));
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk)?;
+            downloaded += chunk.len() as u64;
+            if downloaded - reported >= PROGRESS_STEP {
+                on_progress(progress(downloaded));
+                reported = downloaded;
+            }
+        }
+        if reported != downloaded {
+            on_progress(progress(downloaded));
+        }
+        Ok(())
     }
 
     #[cfg(not(test))]
@@ -71,7 +210,7 @@ impl Binary {
         Ok(TempDir::new("pivx-rust")?.into_path())
     }
 
-    fn new_by_path<T: BinaryDefinition + Send>(
+    pub fn new_by_path<T: BinaryDefinition + Send>(
         path: &str,
         binary_definition: &T,
     ) -> Result<Self, PIVXErrors> {
@@ -79,24 +218,73 @@ impl Binary {
         if !data_dir.exists() {
             std::fs::create_dir_all(&data_dir)?;
         }
-        let handle = Command::new(path)
+        let mut handle = Command::new(path)
             .args(binary_definition.get_binary_args(&data_dir)?)
             .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
             .kill_on_drop(true)
             .spawn()
             .map_err(|_| PIVXErrors::PivxdNotFound)?;
-        Ok(Binary { handle })
+        let stdout = logs::capture(&mut handle);
+        Ok(Binary { handle, stdout })
+    }
+
+    /**
+     * Fetches and decompresses the binary into `dir`, unless it's already there.
+     * The fetched archive is removed afterwards
+     */
+    pub async fn install<T: BinaryDefinition + Send>(
+        dir: &Path,
+        binary_definition: &T,
+    ) -> Result<(), PIVXErrors> {
+        if !binary_definition.get_binary_path(dir).exists() {
+            Self::fetch(dir, binary_definition).await?;
+            let result = binary_definition.decompress_archive(dir);
+            let _ = std::fs::remove_file(dir.join(binary_definition.get_archive_name()));
+            result?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Installs the binary from an archive downloaded beforehand, unless it's already in `dir`.
+     * The archive is checked against the same checksum as a fetched one
+     */
+    pub fn install_from_archive<T: BinaryDefinition + Send>(
+        dir: &Path,
+        archive: &Path,
+        binary_definition: &T,
+    ) -> Result<(), PIVXErrors> {
+        if binary_definition.get_binary_path(dir).exists() {
+            return Ok(());
+        }
+        let digest =
+            sha256::try_digest(archive).map_err(|e| PIVXErrors::WrongSha256Sum(Some(e)))?;
+        if digest != binary_definition.get_sha256sum() {
+            return Err(PIVXErrors::WrongSha256Sum(None));
+        }
+        std::fs::create_dir_all(dir)?;
+        let file_path = dir.join(binary_definition.get_archive_name());
+        if archive.canonicalize()?
+            == dir
+                .canonicalize()?
+                .join(binary_definition.get_archive_name())
+        {
+            // Already where it's decompressed from, and the file is the user's to keep
+            return binary_definition.decompress_archive(dir);
+        }
+        std::fs::copy(archive, &file_path)?;
+        let result = binary_definition.decompress_archive(dir);
+        let _ = std::fs::remove_file(&file_path);
+        result
     }
 
     pub async fn new_by_fetching<T: BinaryDefinition + Send>(
         binary_definition: &T,
     ) -> Result<Self, PIVXErrors> {
         let data_dir = Self::get_data_dir()?;
+        Self::install(&data_dir, binary_definition).await?;
         let binary_path = binary_definition.get_binary_path(&data_dir);
-        if !binary_path.exists() {
-            Self::fetch(&data_dir, binary_definition).await?;
-            binary_definition.decompress_archive(&data_dir)?;
-        }
         Self::new_by_path(&binary_path.to_string_lossy(), binary_definition)
     }
 
@@ -107,6 +295,6 @@ impl Binary {
         &mut self,
         binary_definition: &T,
     ) -> crate::error::Result<()> {
-        binary_definition.wait_for_load(&mut self.handle).await
+        binary_definition.wait_for_load(self.stdout.take()).await
     }
 }
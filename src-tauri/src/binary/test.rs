@@ -23,7 +23,13 @@ impl BinaryDefinition for TestBinary {
     fn get_binary_args(&self, _: &Path) -> Result<Vec<String>, PIVXErrors> {
         unimplemented!()
     }
-    async fn wait_for_load(&self, _handle: &mut Child) -> crate::error::Result<()> {
+    async fn wait_for_load(
+        &self,
+        _stdout: Option<mpsc::Receiver<String>>,
+    ) -> crate::error::Result<()> {
+        Ok(())
+    }
+    async fn verify_archive(&self, _archive: &Path) -> crate::error::Result<()> {
         Ok(())
     }
 }
@@ -64,4 +70,65 @@ mod pivx_fetch {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn resumes_partial_downloads() -> Result<(), PIVXErrors> {
+        let data_dir = Binary::get_data_dir()?;
+        std::fs::write(data_dir.join("a.tar.gz"), "PIVX ")?;
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/")
+            .match_header("range", "bytes=5-")
+            .with_status(206)
+            .with_header("content-range", "bytes 5-15/16")
+            .with_body("Source code")
+            .create_async()
+            .await;
+        let binary_definition = TestBinary { url: server.url() };
+        let mut progress = vec![];
+        Binary::fetch_with_progress(&data_dir, &binary_definition, |p| progress.push(p)).await?;
+
+        let content = std::fs::read_to_string(data_dir.join("a.tar.gz"))?;
+        assert_eq!(content, "PIVX Source code");
+        let downloaded: Vec<_> = progress.iter().map(|p| p.downloaded).collect();
+        assert_eq!(downloaded, vec![5, 16]);
+        assert_eq!(progress[1].total, Some(16));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restarts_when_the_server_ignores_ranges() -> Result<(), PIVXErrors> {
+        let data_dir = Binary::get_data_dir()?;
+        std::fs::write(data_dir.join("a.tar.gz"), "Garbage")?;
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/")
+            .with_body("PIVX Source code")
+            .create_async()
+            .await;
+        let binary_definition = TestBinary { url: server.url() };
+        Binary::fetch(&data_dir, &binary_definition).await?;
+
+        let content = std::fs::read_to_string(data_dir.join("a.tar.gz"))?;
+        assert_eq!(content, "PIVX Source code");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn removes_archives_with_wrong_checksum() -> Result<(), PIVXErrors> {
+        let data_dir = Binary::get_data_dir()?;
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/")
+            .with_body("Malicious code")
+            .create_async()
+            .await;
+        let binary_definition = TestBinary { url: server.url() };
+        assert!(matches!(
+            Binary::fetch(&data_dir, &binary_definition).await,
+            Err(PIVXErrors::WrongSha256Sum(None))
+        ));
+        assert!(!data_dir.join("a.tar.gz").exists());
+        Ok(())
+    }
 }
@@ -28,6 +28,19 @@ pub enum PIVXErrors {
     #[error("Error with Sqlite")]
     SqliteError(#[from] rusqlite::Error),
 
+    #[error("The index was created by a newer version")]
+    IndexTooNew,
+
+    #[error("Invalid index snapshot")]
+    InvalidSnapshot,
+
+    #[error("pivxd hasn't synced up to the tip of the snapshot yet")]
+    SnapshotAheadOfNode,
+
+    #[cfg(feature = "sled")]
+    #[error("Error with sled")]
+    SledError(#[from] sled::Error),
+
     #[error("Tauri error")]
     TauriError(#[from] tauri::Error),
 
@@ -37,14 +50,53 @@ pub enum PIVXErrors {
     #[error("Invalid block")]
     InvalidBlock,
 
+    #[error("The block source can't fetch blocks by height")]
+    NoIndexedBlockSource,
+
+    #[error("Invalid sapling tree")]
+    InvalidSaplingTree,
+
+    #[error("Failed to (de)serialize json")]
+    JsonError(#[from] serde_json::Error),
+
     #[error("Invalid response from RPC")]
     InvalidResponse,
 
-    #[error("Failed to fetch sapling params")]
-    FetchParamsFailed,
+    #[error("pivxd answered with an RPC error")]
+    RpcError(#[from] crate::address_index::pivx_rpc::json_rpc::Error<Option<serde_json::Value>>),
 
     #[error("PIVXD was stopped before loading was finished")]
     PivxdStopped,
+
+    #[error("PIVXD didn't answer RPC calls in time")]
+    PivxdLoadTimeout,
+
+    #[error("No release of this pivxd version for this platform")]
+    UnknownRelease,
+
+    #[error("This pivxd version is not installed")]
+    VersionNotInstalled,
+
+    #[error("There is no pivxd version to roll back to")]
+    NoPreviousVersion,
+
+    #[error("The release is not signed by a known maintainer key")]
+    InvalidSignature,
+
+    #[error("gpg is needed to verify releases but wasn't found")]
+    GpgNotFound,
+
+    #[error("There are no maintainer keys to verify releases with")]
+    NoReleaseKeys,
+
+    #[error("gpg failed to read the maintainer keys")]
+    GpgFailed,
+
+    #[error("The archive has entries that would be unsafe to extract")]
+    UnsafeArchive,
+
+    #[error("The sapling params are missing and can't be downloaded with an offline pivxd source")]
+    SaplingParamsNotFound,
 }
 
 pub type Result<T> = std::result::Result<T, PIVXErrors>;
@@ -0,0 +1,23 @@
+use serde::Serialize;
+use std::sync::OnceLock;
+use tauri::{AppHandle, Manager};
+
+static APP_HANDLE: OnceLock<AppHandle> = OnceLock::new();
+
+/**
+ * Sets the handle events are emitted with, it must be called once the app is set up
+ */
+pub fn set_app_handle(app_handle: AppHandle) {
+    let _ = APP_HANDLE.set(app_handle);
+}
+
+/**
+ * Emits `event` to every window. Events emitted before the app is set up are dropped
+ */
+pub fn emit<S: Serialize + Clone>(event: &str, payload: S) {
+    if let Some(app_handle) = APP_HANDLE.get() {
+        if let Err(err) = app_handle.emit_all(event, payload) {
+            eprintln!("Warning: failed to emit {}: {}", event, err);
+        }
+    }
+}
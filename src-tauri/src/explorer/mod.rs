@@ -2,13 +2,26 @@ use crate::error::PIVXErrors;
 use jsonrpsee::rpc_params;
 use serde::Deserialize;
 use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::sync::OnceCell;
 
+use crate::address::AddressInfo;
+#[cfg(feature = "sled")]
+use crate::address_index::sled_db::SledDb;
+#[cfg(not(feature = "sled"))]
+use crate::address_index::sql_lite::SqlLite;
 use crate::address_index::{
-    database::Database, pivx_rpc::PIVXRpc, sql_lite::SqlLite, types::Vin, AddressIndex,
+    database::Database,
+    pivx_rpc::PIVXRpc,
+    types::{
+        BlockHeader, CompactBlock, Delegation, RawTx, SaplingTree, SnapshotManifest,
+        SpentNullifier, SyncStatus, VerifyReport, Vin,
+    },
+    AddressIndex,
 };
 use crate::binary::Binary;
-use crate::{PIVXDefinition, RPC_PORT};
+use crate::pivx::versions::get_version_manager;
+use crate::RPC_PORT;
 use global_function_macro::generate_global_functions;
 
 type TxHexWithBlockCount = (String, u64, u64);
@@ -20,9 +33,21 @@ where
 {
     address_index: AddressIndex<D>,
     pivx_rpc: PIVXRpc,
+    /// Set once pivxd is loaded, see `Explorer::rpc`
+    pivxd: Arc<OnceCell<Binary>>,
 }
 
-type DefaultExplorer = Explorer<SqlLite>;
+// The database backend is selected with the `sled` feature
+#[cfg(not(feature = "sled"))]
+type DefaultDatabase = SqlLite;
+#[cfg(not(feature = "sled"))]
+const DATABASE_PATH: &str = "/home/duddino/test.sqlite";
+#[cfg(feature = "sled")]
+type DefaultDatabase = SledDb;
+#[cfg(feature = "sled")]
+const DATABASE_PATH: &str = "/home/duddino/test.sled";
+
+type DefaultExplorer = Explorer<DefaultDatabase>;
 
 impl<D> Explorer<D>
 where
@@ -32,8 +57,26 @@ where
         Self {
             address_index,
             pivx_rpc: rpc,
+            pivxd: Arc::default(),
         }
     }
+
+    /**
+     * Returns the RPC client once pivxd is started and loaded.
+     * The first call starts it, so that the index can be served while pivxd loads
+     */
+    async fn rpc(&self) -> crate::error::Result<&PIVXRpc> {
+        self.pivxd
+            .get_or_try_init(|| async {
+                let version_manager = get_version_manager().await;
+                let mut pivx = version_manager.start_pivxd().await?;
+                let pivx_definition = version_manager.get_current_definition()?;
+                pivx.wait_for_load(&pivx_definition).await?;
+                Ok::<_, PIVXErrors>(pivx)
+            })
+            .await?;
+        Ok(&self.pivx_rpc)
+    }
 }
 
 static EXPLORER: OnceCell<DefaultExplorer> = OnceCell::const_new();
@@ -41,29 +84,27 @@ static EXPLORER: OnceCell<DefaultExplorer> = OnceCell::const_new();
 async fn get_explorer() -> &'static DefaultExplorer {
     EXPLORER
         .get_or_init(|| async {
-            let pivx_definition = PIVXDefinition;
-            let mut pivx = Binary::new_by_fetching(&pivx_definition)
-                .await
-                .expect("Failed to run PIVX");
-            pivx.wait_for_load(&pivx_definition).await.unwrap();
-            let pivx_rpc = PIVXRpc::new(&format!("http://127.0.0.1:{}", RPC_PORT), pivx)
+            let pivx_rpc = PIVXRpc::new(&format!("http://127.0.0.1:{}", RPC_PORT))
                 .await
                 .unwrap();
             // FIXME: refactor this to accept HOME
             let address_index = AddressIndex::new(
-                SqlLite::new(PathBuf::from("/home/duddino/test.sqlite"))
+                DefaultDatabase::new(PathBuf::from(DATABASE_PATH))
                     .await
                     .unwrap(),
                 pivx_rpc.clone(),
             );
 
             let explorer = Explorer::new(address_index, pivx_rpc);
-            // Cloning is very cheap, it's just a Pathbuf and some Arcs
+            // Cloning is very cheap, it's just a Pathbuf and some Arcs.
+            // pivxd is started in the background, the index is served meanwhile
             let explorer_clone = explorer.clone();
             tokio::spawn(async move {
-                if let Err(err) = explorer_clone.sync().await {
-                    eprintln!("Warning: Syncing failed with error {}", err);
+                match explorer_clone.rpc().await {
+                    Ok(_) => explorer_clone.address_index.request_sync(),
+                    Err(err) => eprintln!("Warning: failed to start pivxd: {}", err),
                 }
+                explorer_clone.address_index.clone().run_syncs().await;
             });
 
             explorer
@@ -71,25 +112,66 @@ async fn get_explorer() -> &'static DefaultExplorer {
         .await
 }
 
+/// Validates a transparent or shield address, returning its type and network.
+/// It doesn't need pivxd or the index, so it's not an `Explorer` method
+#[tauri::command]
+pub fn explorer_validate_address(address: String) -> AddressInfo {
+    AddressInfo::new(&address)
+}
+
 #[generate_global_functions]
 impl<D> Explorer<D>
 where
     D: Database + Send + Clone,
 {
     pub async fn get_block(&self, block_height: u64) -> crate::error::Result<String> {
-        let block_hash: String = self
-            .pivx_rpc
-            .call("getblockhash", rpc_params![block_height])
-            .await?;
+        let block_hash = match self.address_index.get_header(block_height).await? {
+            Some(header) => header.hash,
+            None => {
+                self.rpc()
+                    .await?
+                    .call("getblockhash", rpc_params![block_height])
+                    .await?
+            }
+        };
+        self.get_block_by_hash(&block_hash).await
+    }
+
+    pub async fn get_block_by_hash(&self, block_hash: &str) -> crate::error::Result<String> {
         let json: serde_json::Value = self
-            .pivx_rpc
+            .rpc()
+            .await?
             .call("getblock", rpc_params![block_hash, 2])
             .await?;
         Ok(json.to_string())
     }
 
+    /// Gets the header of the block at `block_height` from the index
+    pub async fn get_block_header(
+        &self,
+        block_height: u64,
+    ) -> crate::error::Result<Option<BlockHeader>> {
+        self.address_index.get_header(block_height).await
+    }
+
+    /// Gets the header of the block with hash `block_hash` from the index
+    pub async fn get_block_header_by_hash(
+        &self,
+        block_hash: &str,
+    ) -> crate::error::Result<Option<BlockHeader>> {
+        self.address_index.get_header_by_hash(block_hash).await
+    }
+
+    /// Gets the header of the latest block mined at or before `timestamp`
+    pub async fn get_block_at_time(
+        &self,
+        timestamp: u64,
+    ) -> crate::error::Result<Option<BlockHeader>> {
+        self.address_index.get_header_at_time(timestamp).await
+    }
+
     pub async fn get_block_count(&self) -> crate::error::Result<u64> {
-        self.pivx_rpc.call("getblockcount", rpc_params![]).await
+        self.rpc().await?.call("getblockcount", rpc_params![]).await
     }
 
     /// Gets all raw transactions containing one of `address`
@@ -120,7 +202,53 @@ where
         }
     }
 
-    /// Gets raw transaction in hex format
+    /// Gets the active cold stake delegations to `staker_address`
+    pub async fn get_delegations(
+        &self,
+        staker_address: &str,
+    ) -> crate::error::Result<Vec<Delegation>> {
+        self.address_index.get_delegations(staker_address).await
+    }
+
+    /// Gets the sum in satoshis of the active delegations to `staker_address`
+    pub async fn get_delegated_balance(&self, staker_address: &str) -> crate::error::Result<u64> {
+        self.address_index
+            .get_delegated_balance(staker_address)
+            .await
+    }
+
+    /// Gets the shield data of the blocks between `from` and `to` inclusive,
+    /// so that notes can be trial decrypted without fetching full blocks.
+    /// Blocks without shield transactions are omitted
+    pub async fn get_compact_blocks(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> crate::error::Result<Vec<CompactBlock>> {
+        self.address_index.get_compact_blocks(from, to).await
+    }
+
+    /// Checks which of `nullifiers` have been revealed on chain,
+    /// returning the tx and height that spent them.
+    /// Nullifiers that are still unspent are omitted
+    pub async fn check_nullifiers(
+        &self,
+        nullifiers: Vec<&str>,
+    ) -> crate::error::Result<Vec<SpentNullifier>> {
+        self.address_index.get_spent_nullifiers(&nullifiers).await
+    }
+
+    /// Gets the last sapling tree snapshot at or below `block_height`,
+    /// so that shield sync can start from there instead of from genesis
+    pub async fn get_sapling_tree(
+        &self,
+        block_height: u64,
+    ) -> crate::error::Result<Option<SaplingTree>> {
+        self.address_index.get_sapling_tree(block_height).await
+    }
+
+    /// Gets raw transaction in hex format.
+    /// Confirmed transactions are cached, so they don't need pivxd after the first request
     pub async fn get_transaction(&self, txid: &str) -> crate::error::Result<TxHexWithBlockCount> {
         use serde::Deserialize;
         #[derive(Deserialize)]
@@ -135,31 +263,115 @@ where
             time: u64,
         }
 
+        if let Some(raw_tx) = self.address_index.get_raw_tx(txid).await? {
+            return Ok((raw_tx.hex, raw_tx.height, raw_tx.time));
+        }
         let TxResponse {
             hex,
             blockhash,
             confirmations,
         } = self
-            .pivx_rpc
+            .rpc()
+            .await?
             .call("getrawtransaction", rpc_params![txid, true])
             .await?;
         if confirmations == 0 {
             return Err(PIVXErrors::InvalidResponse);
         }
-        let BlockResponse { height, time } = self
-            .pivx_rpc
-            .call("getblock", rpc_params![blockhash])
-            .await?;
+        let (height, time) = match self.address_index.get_header_by_hash(&blockhash).await? {
+            Some(header) => (header.height, header.time),
+            None => {
+                let BlockResponse { height, time } = self
+                    .rpc()
+                    .await?
+                    .call("getblock", rpc_params![&blockhash])
+                    .await?;
+                (height, time)
+            }
+        };
+        // Cloning is very cheap, and the database is only written through the clone.
+        // The tx was fetched anyway, so failing to cache it is not an error
+        let raw_tx = RawTx {
+            txid: txid.to_owned(),
+            hex: hex.clone(),
+            block_hash: blockhash,
+            height,
+            time,
+        };
+        if let Err(err) = self.address_index.clone().store_raw_tx(&raw_tx).await {
+            eprintln!("Warning: failed to cache tx {}: {}", txid, err);
+        }
         Ok((hex, height, time))
     }
 
     pub async fn send_transaction(&self, transaction: &str) -> crate::error::Result<String> {
-        self.pivx_rpc
+        self.rpc()
+            .await?
             .call("sendrawtransaction", rpc_params![transaction])
             .await
     }
 
+    /// Checks the index against pivxd between `from` and `to` inclusive, up to the last indexed block.
+    /// If `sample_size` is set only that many blocks are checked,
+    /// and with `repair` the mismatching range is indexed again while the sync is paused
+    pub async fn verify_index(
+        &self,
+        from: u64,
+        to: u64,
+        sample_size: Option<u64>,
+        repair: bool,
+    ) -> crate::error::Result<VerifyReport> {
+        self.address_index
+            .clone()
+            .verify(from, to, sample_size, repair)
+            .await
+    }
+
+    /// Starts a sync in the background once the running one, if any, returns.
+    /// The sync keeps waiting for new blocks at the tip until it's cancelled
     pub async fn sync(&self) -> crate::error::Result<()> {
-        self.address_index.clone().sync().await
+        self.rpc().await?;
+        self.address_index.request_sync();
+        Ok(())
+    }
+
+    /// Cancels the running sync, the blocks indexed so far are kept
+    pub async fn cancel_sync(&self) -> crate::error::Result<()> {
+        self.address_index.cancel_sync().await;
+        Ok(())
+    }
+
+    /// Stops the running sync and syncs again from `block_height` in the background.
+    /// Resolves once the index is rewound
+    pub async fn reindex(&self, block_height: u64) -> crate::error::Result<()> {
+        self.address_index.clone().reindex(block_height).await
+    }
+
+    /// Wipes the index and syncs it from scratch in the background,
+    /// the sync status is `rebuilding` until it reaches the tip. Resolves once the index is wiped
+    pub async fn rebuild_index(&self) -> crate::error::Result<()> {
+        self.address_index.clone().rebuild().await
+    }
+
+    /// Writes the index to a compressed snapshot file at `path`, returning its tip and checksum
+    pub async fn export_snapshot(&self, path: &str) -> crate::error::Result<SnapshotManifest> {
+        self.address_index
+            .export_snapshot(PathBuf::from(path))
+            .await
+    }
+
+    /// Replaces the index with the snapshot at `path` if its tip matches pivxd,
+    /// then syncs forward in the background. Resolves once the snapshot is restored.
+    /// Fails while pivxd is still syncing below the tip of the snapshot, it can be retried later
+    pub async fn import_snapshot(&self, path: &str) -> crate::error::Result<()> {
+        self.rpc().await?;
+        self.address_index
+            .clone()
+            .import_snapshot(PathBuf::from(path))
+            .await
+    }
+
+    pub async fn get_sync_status(&self) -> crate::error::Result<SyncStatus> {
+        self.address_index.get_sync_status().await
     }
 }
@@ -1,11 +1,11 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use pivx::PIVXDefinition;
-
+mod address;
 mod address_index;
 mod binary;
 mod error;
+mod events;
 mod explorer;
 mod pivx;
 
@@ -14,9 +14,15 @@ pub const RPC_USERNAME: &str = "username";
 pub const RPC_PASSWORD: &str = "password";
 
 fn main() {
+    use binary::logs::auto_generated::*;
     use explorer::auto_generated::*;
+    use pivx::versions::auto_generated::*;
 
     tauri::Builder::default()
+        .setup(|app| {
+            events::set_app_handle(app.handle());
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             explorer_get_block,
             explorer_get_block_count,
@@ -24,7 +30,32 @@ fn main() {
             explorer_get_transaction,
             explorer_send_transaction,
             explorer_get_tx_from_vin,
+            explorer_get_delegations,
+            explorer_get_delegated_balance,
+            explorer_get_compact_blocks,
+            explorer_check_nullifiers,
+            explorer_get_sapling_tree,
+            explorer_get_block_by_hash,
+            explorer_get_block_header,
+            explorer_get_block_header_by_hash,
+            explorer_get_block_at_time,
+            explorer::explorer_validate_address,
+            explorer_verify_index,
             explorer_sync,
+            explorer_cancel_sync,
+            explorer_reindex,
+            explorer_rebuild_index,
+            explorer_get_sync_status,
+            explorer_export_snapshot,
+            explorer_import_snapshot,
+            version_manager_get_versions,
+            version_manager_install_version,
+            version_manager_switch_version,
+            version_manager_upgrade,
+            version_manager_rollback,
+            version_manager_set_pivxd_source,
+            version_manager_set_load_timeout,
+            node_get_logs,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,70 @@
+use std::fs::File;
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::PIVXErrors;
+use flate2::read::GzDecoder;
+use tar::{Archive, EntryType};
+
+/**
+ * Returns whether `path` stays inside the directory it's relative to
+ */
+fn is_contained(path: &Path) -> bool {
+    resolve(Path::new(""), path).is_some()
+}
+
+/**
+ * Lexically joins `path` to `base`, returns None if the result is absolute or goes above `base`
+ */
+fn resolve(base: &Path, path: &Path) -> Option<PathBuf> {
+    let mut resolved: Vec<_> = base.components().collect();
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => resolved.push(component),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop()?;
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(resolved.iter().collect())
+}
+
+/**
+ * Extracts the gzipped tar at `archive` into `dir`. Archives with entries or links
+ * pointing outside of `dir`, or with special files, are rejected as a whole.
+ * Entries extracted before a rejected one are left in `dir`, so it should be a staging directory
+ */
+pub fn extract_archive(archive: &Path, dir: &Path) -> crate::error::Result<()> {
+    let mut tarball = Archive::new(GzDecoder::new(File::open(archive)?));
+    for entry in tarball.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if !is_contained(&path) {
+            return Err(PIVXErrors::UnsafeArchive);
+        }
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Directory => {}
+            // Symlinks are relative to their directory
+            EntryType::Symlink => {
+                let target = entry.link_name()?.ok_or(PIVXErrors::UnsafeArchive)?;
+                let parent = path.parent().unwrap_or(Path::new(""));
+                if resolve(parent, &target).is_none() {
+                    return Err(PIVXErrors::UnsafeArchive);
+                }
+            }
+            // Hard links are relative to the root of the archive
+            EntryType::Link => {
+                let target = entry.link_name()?.ok_or(PIVXErrors::UnsafeArchive)?;
+                if !is_contained(&target) {
+                    return Err(PIVXErrors::UnsafeArchive);
+                }
+            }
+            _ => return Err(PIVXErrors::UnsafeArchive),
+        }
+        if !entry.unpack_in(dir)? {
+            return Err(PIVXErrors::UnsafeArchive);
+        }
+    }
+    Ok(())
+}
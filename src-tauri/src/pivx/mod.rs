@@ -1,68 +1,134 @@
+pub mod archive;
+pub mod params;
+pub mod readiness;
+pub mod signature;
 #[cfg(test)]
 mod test;
+pub mod versions;
 
 use crate::error::PIVXErrors;
-use flate2::read::GzDecoder;
-use std::fs::File;
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use tar::Archive;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use std::time::Duration;
+use tokio::sync::mpsc;
 
+use crate::address_index::pivx_rpc;
 use crate::binary::BinaryDefinition;
+use versions::PivxRelease;
 
-pub struct PIVXDefinition;
+/**
+ * Returns the directory the archive of `version` extracts to
+ */
+fn pivx_dir(dir: &Path, version: &str) -> PathBuf {
+    dir.join(format!("pivx-{}", version))
+}
+
+/**
+ * Returns the path of pivxd `version` once its archive is extracted into `dir`
+ */
+pub fn binary_path(dir: &Path, version: &str) -> PathBuf {
+    pivx_dir(dir, version).join("bin").join("pivxd")
+}
+
+pub struct PIVXDefinition {
+    release: PivxRelease,
+    archive_name: String,
+    rpc_url: String,
+    load_timeout: Duration,
+}
 
 impl PIVXDefinition {
-    fn inner_decompress_archive(&self, dir: &Path) -> Result<(), PIVXErrors> {
-        let mut tarball = Archive::new(GzDecoder::new(File::open(dir.join("pivxd.tar.gz"))?));
-        tarball.unpack(dir)?;
-        Ok(())
+    pub fn new(release: PivxRelease) -> Self {
+        Self {
+            // Versioned, so that a partial download is never resumed into another version
+            archive_name: format!("pivx-{}.tar.gz", release.version),
+            release,
+            rpc_url: format!("http://127.0.0.1:{}", crate::RPC_PORT),
+            load_timeout: readiness::LOAD_TIMEOUT,
+        }
     }
 
-    fn inner_install_params(&self, dir: &Path) -> Result<(), PIVXErrors> {
-        let pivx_dir = dir.join("pivx-5.6.1");
-        let script_path = pivx_dir.join("install-params.sh");
-        let mut handle = Command::new(script_path)
-            .current_dir(pivx_dir)
-            .spawn()
-            .map_err(|_| PIVXErrors::FetchParamsFailed)?;
-        let status = handle.wait().map_err(|_| PIVXErrors::FetchParamsFailed)?;
-        match status.success() {
-            true => Ok(()),
-            false => Err(PIVXErrors::FetchParamsFailed),
+    /**
+     * Sets the RPC server polled to know when pivxd is loaded
+     */
+    pub fn with_rpc_url(mut self, rpc_url: &str) -> Self {
+        self.rpc_url = rpc_url.to_owned();
+        self
+    }
+
+    /**
+     * Sets how long pivxd may not answer RPC calls before loading fails
+     */
+    pub fn with_load_timeout(mut self, load_timeout: Duration) -> Self {
+        self.load_timeout = load_timeout;
+        self
+    }
+
+    /**
+     * Extracts the archive into a staging directory and moves the release into place
+     * once it's checked to contain pivxd. The staging directory is removed,
+     * the archive is left to the caller since it may be the user's own file
+     */
+    fn inner_decompress_archive(&self, dir: &Path) -> Result<(), PIVXErrors> {
+        let version = &self.release.version;
+        let archive_path = dir.join(self.get_archive_name());
+        let staging_dir = dir.join(format!("pivx-{}.staging", version));
+        if staging_dir.exists() {
+            std::fs::remove_dir_all(&staging_dir)?;
         }
+        std::fs::create_dir_all(&staging_dir)?;
+        let result = archive::extract_archive(&archive_path, &staging_dir).and_then(|_| {
+            if !binary_path(&staging_dir, version).is_file() {
+                return Err(PIVXErrors::PivxdNotFound);
+            }
+            let install_dir = pivx_dir(dir, version);
+            // Leftovers of an install without pivxd, or it wouldn't be decompressed again
+            if install_dir.exists() {
+                std::fs::remove_dir_all(&install_dir)?;
+            }
+            std::fs::rename(pivx_dir(&staging_dir, version), install_dir)?;
+            Ok(())
+        });
+        let _ = std::fs::remove_dir_all(&staging_dir);
+        result
     }
 }
 
 impl BinaryDefinition for PIVXDefinition {
     fn decompress_archive(&self, dir: &Path) -> Result<(), PIVXErrors> {
-        self.inner_decompress_archive(dir)?;
-        self.inner_install_params(dir)
+        self.inner_decompress_archive(dir)
     }
 
     fn get_url(&self) -> &str {
-        #[cfg(target_os = "linux")]
-	return "https://github.com/PIVX-Project/PIVX/releases/download/v5.6.1/pivx-5.6.1-x86_64-linux-gnu.tar.gz";
+        &self.release.url
+    }
 
-        #[allow(unreachable_code)]
-        {
-            panic!("Unsupported OS")
+    async fn verify_archive(&self, archive: &Path) -> crate::error::Result<()> {
+        let response = reqwest::get(&self.release.sha256sums_url).await?;
+        if !response.status().is_success() {
+            return Err(PIVXErrors::ServerError);
+        }
+        let signed_sums = response.bytes().await?;
+        let work_dir = archive.with_extension("verify");
+        let sums =
+            signature::verify_signed_sums(&work_dir, signature::RELEASE_KEYS, &signed_sums).await?;
+        // Sums are listed by the archive name in the release, not the local one
+        let file_name = self.release.url.rsplit('/').next().unwrap_or_default();
+        let sum = signature::find_sum(&sums, file_name).ok_or(PIVXErrors::InvalidSignature)?;
+        let digest =
+            sha256::try_digest(archive).map_err(|e| PIVXErrors::WrongSha256Sum(Some(e)))?;
+        match digest == sum {
+            true => Ok(()),
+            false => Err(PIVXErrors::InvalidSignature),
         }
     }
 
     fn get_sha256sum(&self) -> &str {
-        #[cfg(target_os = "linux")]
-        return "6704625c63ff73da8c57f0fbb1dab6f1e4bd8f62c17467e05f52a64012a0ee2f";
-        #[allow(unreachable_code)]
-        {
-            panic!("Unsupported OS")
-        }
+        &self.release.sha256
     }
 
     fn get_archive_name(&self) -> &str {
         #[cfg(target_os = "linux")]
-        return "pivxd.tar.gz";
+        return &self.archive_name;
 
         #[allow(unreachable_code)]
         {
@@ -71,7 +137,7 @@ impl BinaryDefinition for PIVXDefinition {
     }
 
     fn get_binary_path(&self, base_dir: &Path) -> PathBuf {
-        base_dir.join("pivx-5.6.1").join("bin").join("pivxd")
+        binary_path(base_dir, &self.release.version)
     }
 
     fn get_binary_args(&self, base_dir: &Path) -> Result<Vec<String>, PIVXErrors> {
@@ -85,26 +151,11 @@ impl BinaryDefinition for PIVXDefinition {
         Ok(args.split(" ").map(|s| s.to_string()).collect::<Vec<_>>())
     }
 
-    async fn wait_for_load(&self, handle: &mut tokio::process::Child) -> crate::error::Result<()> {
-        let stdout = handle.stdout.take();
-        match stdout {
-            Some(stdout) => {
-                let mut reader = BufReader::new(stdout);
-                let mut line = String::new();
-                loop {
-                    let read_bytes = reader.read_line(&mut line).await?;
-                    if read_bytes == 0 {
-                        return Err(PIVXErrors::PivxdStopped);
-                    }
-                    if line.contains("asking peer for sporks") {
-                        break;
-                    }
-                }
-            }
-            None => eprintln!(
-                "Warning: couldn't wait for load because no stdout is attached to the handle."
-            ),
-        }
-        Ok(())
+    async fn wait_for_load(
+        &self,
+        stdout: Option<mpsc::Receiver<String>>,
+    ) -> crate::error::Result<()> {
+        let client = pivx_rpc::new_client(&self.rpc_url)?;
+        readiness::wait_for_rpc(&client, stdout, self.load_timeout).await
     }
 }
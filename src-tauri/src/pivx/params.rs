@@ -0,0 +1,126 @@
+use std::path::{Path, PathBuf};
+
+use crate::binary::{Binary, DownloadProgress, DOWNLOAD_PROGRESS_EVENT};
+use crate::error::PIVXErrors;
+
+/// The params are the same for every Sapling chain, so they're fetched from the Zcash mirror
+const PARAMS_URL: &str = "https://download.z.cash/downloads";
+
+/// Name and sha256 of the params pivxd needs for shield transactions
+pub const SAPLING_PARAMS: [(&str, &str); 2] = [
+    (
+        "sapling-spend.params",
+        "8e48ffd23abb3a5fd9c5589204f32d9c31285a04b78096ba40a79b75677efc13",
+    ),
+    (
+        "sapling-output.params",
+        "2f0ebbcbb9bb0bcffe95a397e7eba89c29eb4dde6191c339db88570e3f3fb0e4",
+    ),
+];
+
+/**
+ * Returns the directory pivxd loads the params from
+ */
+pub fn get_params_dir() -> Result<PathBuf, PIVXErrors> {
+    let home = dirs::home_dir().ok_or(PIVXErrors::NoDataDir)?;
+    #[cfg(target_os = "macos")]
+    return Ok(home
+        .join("Library")
+        .join("Application Support")
+        .join("PIVXParams"));
+    #[cfg(target_os = "windows")]
+    return Ok(dirs::data_dir()
+        .ok_or(PIVXErrors::NoDataDir)?
+        .join("PIVXParams"));
+
+    #[allow(unreachable_code)]
+    {
+        Ok(home.join(".pivx-params"))
+    }
+}
+
+/**
+ * Returns whether the file at `path` exists with `sha256`
+ */
+fn is_valid(path: &Path, sha256: &str) -> bool {
+    matches!(sha256::try_digest(path), Ok(digest) if digest == sha256)
+}
+
+/**
+ * Downloads the params that are missing or invalid into the params dir.
+ * Progress is emitted to the UI as `DOWNLOAD_PROGRESS_EVENT`
+ */
+pub async fn install_params() -> crate::error::Result<()> {
+    install_params_from(
+        &get_params_dir()?,
+        PARAMS_URL,
+        &SAPLING_PARAMS,
+        |progress| crate::events::emit(DOWNLOAD_PROGRESS_EVENT, progress),
+    )
+    .await
+}
+
+/**
+ * Downloads each of `params` from `base_url` into `dir`, unless it's there with the right sha256.
+ * Partially downloaded params are resumed
+ */
+pub async fn install_params_from(
+    dir: &Path,
+    base_url: &str,
+    params: &[(&str, &str)],
+    mut on_progress: impl FnMut(DownloadProgress),
+) -> crate::error::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    for (name, sha256) in params {
+        let path = dir.join(name);
+        let existed = path.exists();
+        if existed && is_valid(&path, sha256) {
+            continue;
+        }
+        let url = format!("{}/{}", base_url, name);
+        match Binary::download_verified(&url, &path, sha256, &mut on_progress).await {
+            // A complete but corrupted file can't be resumed, it has been removed so start over
+            Err(PIVXErrors::WrongSha256Sum(None)) if existed => {
+                Binary::download_verified(&url, &path, sha256, &mut on_progress).await?
+            }
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Copies the params that are missing or invalid into the params dir from `source_dir`,
+ * for offline pivxd sources
+ */
+pub fn install_local_params(source_dir: Option<&Path>) -> crate::error::Result<()> {
+    copy_params_from(&get_params_dir()?, source_dir, &SAPLING_PARAMS)
+}
+
+/**
+ * Copies each of `params` from `source_dir` into `dir`, unless it's there with the right sha256.
+ * Fails with `SaplingParamsNotFound` if a param isn't valid in either
+ */
+pub fn copy_params_from(
+    dir: &Path,
+    source_dir: Option<&Path>,
+    params: &[(&str, &str)],
+) -> crate::error::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    for (name, sha256) in params {
+        let path = dir.join(name);
+        if is_valid(&path, sha256) {
+            continue;
+        }
+        match source_dir.map(|source_dir| source_dir.join(name)) {
+            Some(source) if is_valid(&source, sha256) => {
+                // Copied next to the param and renamed, so that it's never partially written
+                let tmp_path = dir.join(format!("{}.tmp", name));
+                std::fs::copy(source, &tmp_path)?;
+                std::fs::rename(tmp_path, path)?;
+            }
+            _ => return Err(PIVXErrors::SaplingParamsNotFound),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::address_index::pivx_rpc::json_rpc::{Error, HttpClient};
+use crate::error::PIVXErrors;
+use futures::future::{self, Either};
+use jsonrpsee::rpc_params;
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// pivxd answers with this code while it's still loading the block index and wallet
+const RPC_IN_WARMUP: i32 = -28;
+/// Time between two RPC polls
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Default time pivxd may not answer RPC calls while starting
+pub const LOAD_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Warm-up answers extend the wait up to this many load timeouts, so a hung load still fails
+const MAX_WARMUP_TIMEOUTS: u32 = 6;
+/// Logged by pivxd once it's loaded, used when RPC doesn't answer in time
+const LOADED_LOG_LINE: &str = "asking peer for sporks";
+
+enum RpcStatus {
+    Ready,
+    /// pivxd answers with its loading progress, but it's still loading
+    WarmingUp(String),
+    Unreachable,
+}
+
+/**
+ * Returns whether pivxd answers `getblockchaininfo` and `getnetworkinfo`.
+ * Connection and warm-up errors mean it's still loading, any other RPC error is returned
+ */
+async fn poll_rpc(client: &HttpClient) -> crate::error::Result<RpcStatus> {
+    for method in ["getblockchaininfo", "getnetworkinfo"] {
+        match client
+            .request::<Value, Option<Value>, _>(method, rpc_params![])
+            .await
+        {
+            Ok(_) => {}
+            Err(Error::JSONRpc(err)) if err.code == RPC_IN_WARMUP => {
+                return Ok(RpcStatus::WarmingUp(err.message))
+            }
+            Err(Error::Fetch(_)) => return Ok(RpcStatus::Unreachable),
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(RpcStatus::Ready)
+}
+
+/**
+ * Resolves once pivxd answers RPC calls, polling it every `POLL_INTERVAL`.
+ * It fails if pivxd is unreachable for `timeout`. Warm-up answers restart the wait since
+ * loading a large chain can take longer than `timeout`, but only up to `MAX_WARMUP_TIMEOUTS` times it.
+ * The lines of `log` are scanned in the meantime: its end means pivxd stopped, and if RPC
+ * doesn't answer in time pivxd is considered loaded when it logged `LOADED_LOG_LINE`
+ */
+pub async fn wait_for_rpc(
+    client: &HttpClient,
+    log: Option<mpsc::Receiver<String>>,
+    timeout: Duration,
+) -> crate::error::Result<()> {
+    let logged_load = AtomicBool::new(false);
+    let scan_log = async {
+        match log {
+            Some(mut log) => loop {
+                match log.recv().await {
+                    Some(line) if line.contains(LOADED_LOG_LINE) => {
+                        logged_load.store(true, Ordering::Relaxed)
+                    }
+                    Some(_) => {}
+                    None => return Err(PIVXErrors::PivxdStopped),
+                }
+            },
+            None => future::pending::<crate::error::Result<()>>().await,
+        }
+    };
+    let poll = async {
+        let max_deadline = Instant::now() + timeout * MAX_WARMUP_TIMEOUTS;
+        let mut deadline = Instant::now() + timeout;
+        let mut progress = String::new();
+        loop {
+            match poll_rpc(client).await? {
+                RpcStatus::Ready => return Ok(()),
+                RpcStatus::WarmingUp(message) => {
+                    deadline = (Instant::now() + timeout).min(max_deadline);
+                    if message != progress {
+                        eprintln!("pivxd is loading: {}", message);
+                        progress = message;
+                    }
+                }
+                RpcStatus::Unreachable => {}
+            }
+            if Instant::now() >= deadline {
+                if logged_load.load(Ordering::Relaxed) {
+                    eprintln!("Warning: pivxd doesn't answer RPC calls but logged it's loaded");
+                    return Ok(());
+                }
+                return Err(PIVXErrors::PivxdLoadTimeout);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    };
+    let (Either::Left((result, _)) | Either::Right((result, _))) =
+        future::select(Box::pin(scan_log), Box::pin(poll)).await;
+    result
+}
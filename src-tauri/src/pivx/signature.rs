@@ -0,0 +1,115 @@
+use std::path::Path;
+use std::process::Stdio;
+use tokio::process::Command;
+
+use crate::error::PIVXErrors;
+
+/// Armored public keys of the maintainers signing the PIVX releases
+pub const RELEASE_KEYS: &str = include_str!("release-keys.asc");
+
+/**
+ * Runs a gpg command, failing with `failure` if it exits with an error
+ */
+async fn run(command: &mut Command, failure: PIVXErrors) -> crate::error::Result<()> {
+    let status = command
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .map_err(|_| PIVXErrors::GpgNotFound)?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(failure),
+    }
+}
+
+/**
+ * Returns how many public keys gpg finds in the armored `keys`.
+ * gpg files are written to `work_dir`
+ */
+pub async fn count_keys(work_dir: &Path, keys: &str) -> crate::error::Result<usize> {
+    let keys_path = work_dir.join("keys.asc");
+    std::fs::write(&keys_path, keys)?;
+    // gpg fails when there is no key at all, which is counted as none
+    let output = Command::new("gpg")
+        .arg("--homedir")
+        .arg(work_dir)
+        .args(["--batch", "--with-colons", "--show-keys"])
+        .arg(&keys_path)
+        .stderr(Stdio::null())
+        .output()
+        .await
+        .map_err(|_| PIVXErrors::GpgNotFound)?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| line.starts_with("pub:"))
+        .count())
+}
+
+/**
+ * Checks the clearsigned `signed_sums` against the armored `keys` with gpgv,
+ * returning the signed text so that unsigned lines around it are never trusted.
+ * Fails with `NoReleaseKeys` if `keys` has none, rather than blaming the signature.
+ * gpg files are written to `work_dir`, which is removed afterwards
+ */
+pub async fn verify_signed_sums(
+    work_dir: &Path,
+    keys: &str,
+    signed_sums: &[u8],
+) -> crate::error::Result<String> {
+    std::fs::create_dir_all(work_dir)?;
+    let result = inner_verify_signed_sums(work_dir, keys, signed_sums).await;
+    let _ = std::fs::remove_dir_all(work_dir);
+    result
+}
+
+async fn inner_verify_signed_sums(
+    work_dir: &Path,
+    keys: &str,
+    signed_sums: &[u8],
+) -> crate::error::Result<String> {
+    if count_keys(work_dir, keys).await? == 0 {
+        return Err(PIVXErrors::NoReleaseKeys);
+    }
+    let keys_path = work_dir.join("keys.asc");
+    let keyring_path = work_dir.join("keyring.gpg");
+    let signed_path = work_dir.join("SHA256SUMS.asc");
+    let sums_path = work_dir.join("SHA256SUMS");
+    std::fs::write(&signed_path, signed_sums)?;
+    // gpgv only reads binary keyrings, and a separate home keeps the user's keys out.
+    // Failing to convert them is a problem with gpg, not with the release
+    run(
+        Command::new("gpg")
+            .arg("--homedir")
+            .arg(work_dir)
+            .args(["--batch", "--yes", "--dearmor", "--output"])
+            .arg(&keyring_path)
+            .arg(&keys_path),
+        PIVXErrors::GpgFailed,
+    )
+    .await?;
+    run(
+        Command::new("gpgv")
+            .arg("--homedir")
+            .arg(work_dir)
+            .arg("--keyring")
+            .arg(&keyring_path)
+            .arg("--output")
+            .arg(&sums_path)
+            .arg(&signed_path),
+        PIVXErrors::InvalidSignature,
+    )
+    .await?;
+    Ok(std::fs::read_to_string(sums_path)?)
+}
+
+/**
+ * Finds the sum of `file_name` in the output of sha256sum
+ */
+pub fn find_sum<'a>(sums: &'a str, file_name: &str) -> Option<&'a str> {
+    sums.lines().find_map(|line| {
+        let (sum, name) = line.split_once(char::is_whitespace)?;
+        // A leading * marks files hashed in binary mode
+        (name.trim_start().trim_start_matches('*') == file_name).then_some(sum)
+    })
+}
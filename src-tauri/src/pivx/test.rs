@@ -23,8 +23,7 @@ mod pivx_fetch {
             0x00, 0x00, 0xfe, 0xe8, 0x01, 0x83, 0xad, 0x18, 0xb3, 0x00, 0x28, 0x00, 0x00,
         ];
         file.write_all(&data)?;
-        let pivx_def = PIVXDefinition;
-        pivx_def.inner_decompress_archive(&data_dir)?;
+        archive::extract_archive(&data_dir.join("pivxd.tar.gz"), &data_dir)?;
 
         let mut dirs: Vec<_> = std::fs::read_dir(data_dir)?
             .filter_map(|d| {
@@ -44,3 +43,674 @@ mod pivx_fetch {
         Ok(())
     }
 }
+
+mod version_manager {
+    use super::*;
+    use crate::pivx::versions::*;
+    use std::collections::HashMap;
+    use tempdir::TempDir;
+
+    fn release(version: &str) -> ManifestRelease {
+        ManifestRelease {
+            version: version.to_owned(),
+            sha256sums_url: format!("https://example.com/{}/SHA256SUMS.asc", version),
+            platforms: HashMap::from([(
+                PLATFORM.to_owned(),
+                PlatformRelease {
+                    url: format!("https://example.com/pivx-{}.tar.gz", version),
+                    sha256: "sha256".to_owned(),
+                },
+            )]),
+        }
+    }
+
+    fn install(dir: &Path, version: &str) -> Result<(), PIVXErrors> {
+        let path = binary_path(dir, version);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        File::create(path)?;
+        Ok(())
+    }
+
+    /**
+     * Writes an archive of `version` with a pivxd at `path`, returning its sha256
+     */
+    fn release_archive(path: &Path, version: &str) -> Result<String, PIVXErrors> {
+        let encoder = flate2::write::GzEncoder::new(File::create(path)?, Default::default());
+        let mut builder = tar::Builder::new(encoder);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(0);
+        header.set_mode(0o755);
+        header.set_cksum();
+        let name = format!("pivx-{}/bin/pivxd", version);
+        builder.append_data(&mut header, name, std::io::empty())?;
+        builder.into_inner()?.finish()?;
+        Ok(sha256::try_digest(path)?)
+    }
+
+    #[test]
+    fn parses_the_bundled_manifest() -> Result<(), PIVXErrors> {
+        let manifest = ReleaseManifest::bundled()?;
+        assert!(manifest
+            .releases
+            .iter()
+            .any(|release| release.version == "5.6.1"));
+        Ok(())
+    }
+
+    #[test]
+    fn sorts_available_versions() {
+        let manifest = ReleaseManifest {
+            releases: vec![release("5.10.0"), release("5.6.1"), release("5.9.0")],
+        };
+        assert_eq!(
+            manifest.get_available_versions(),
+            vec!["5.6.1", "5.9.0", "5.10.0"]
+        );
+        assert!(matches!(
+            manifest.get_release("4.0.0"),
+            Err(PIVXErrors::UnknownRelease)
+        ));
+    }
+
+    #[tokio::test]
+    async fn switches_and_rolls_back() -> Result<(), PIVXErrors> {
+        let temp_dir = TempDir::new("pivx-versions")?;
+        let dir = temp_dir.path();
+        let manifest = ReleaseManifest {
+            releases: vec![release("5.6.1"), release("5.7.0")],
+        };
+        let version_manager = VersionManager::new(dir.to_owned(), manifest);
+        // Installs from before versions were managed keep running their version
+        install(dir, "5.6.1")?;
+        let versions = version_manager.get_versions().await?;
+        assert_eq!(versions.current, "5.6.1");
+        assert_eq!(versions.previous, None);
+        assert_eq!(versions.installed, vec!["5.6.1"]);
+        assert_eq!(versions.available, vec!["5.6.1", "5.7.0"]);
+        // Starting pivxd pins the version, so that installing a newer one doesn't switch
+        version_manager.get_current_definition()?;
+        assert!(matches!(
+            version_manager.rollback().await,
+            Err(PIVXErrors::NoPreviousVersion)
+        ));
+        assert!(matches!(
+            version_manager.switch_version("5.7.0").await,
+            Err(PIVXErrors::VersionNotInstalled)
+        ));
+
+        install(dir, "5.7.0")?;
+        assert_eq!(version_manager.get_versions().await?.current, "5.6.1");
+        version_manager.switch_version("5.7.0").await?;
+        let versions = version_manager.get_versions().await?;
+        assert_eq!(versions.current, "5.7.0");
+        assert_eq!(versions.previous.as_deref(), Some("5.6.1"));
+        assert_eq!(
+            version_manager
+                .get_current_definition()?
+                .get_binary_path(dir),
+            binary_path(dir, "5.7.0")
+        );
+
+        assert_eq!(version_manager.rollback().await?, "5.6.1");
+        let versions = version_manager.get_versions().await?;
+        assert_eq!(versions.current, "5.6.1");
+        assert_eq!(versions.previous.as_deref(), Some("5.7.0"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn persists_the_load_timeout() -> Result<(), PIVXErrors> {
+        let temp_dir = TempDir::new("pivx-versions")?;
+        let dir = temp_dir.path();
+        let manifest = || ReleaseManifest {
+            releases: vec![release("5.6.1")],
+        };
+        let version_manager = VersionManager::new(dir.to_owned(), manifest());
+        let default = readiness::LOAD_TIMEOUT;
+        assert_eq!(
+            version_manager.get_current_definition()?.load_timeout,
+            default
+        );
+        assert_eq!(
+            version_manager.get_versions().await?.load_timeout,
+            default.as_secs()
+        );
+
+        version_manager.set_load_timeout(Some(3600)).await?;
+        // The timeout is kept across restarts
+        let version_manager = VersionManager::new(dir.to_owned(), manifest());
+        assert_eq!(version_manager.get_versions().await?.load_timeout, 3600);
+        assert_eq!(
+            version_manager.get_current_definition()?.load_timeout,
+            Duration::from_secs(3600)
+        );
+
+        version_manager.set_load_timeout(None).await?;
+        assert_eq!(
+            version_manager.get_current_definition()?.load_timeout,
+            default
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn installs_from_local_sources() -> Result<(), PIVXErrors> {
+        let temp_dir = TempDir::new("pivx-offline")?;
+        let dir = temp_dir.path().join("data");
+        let archive = temp_dir.path().join("pivx-5.7.0.tar.gz");
+        let mut newer = release("5.7.0");
+        newer.platforms.get_mut(PLATFORM).unwrap().sha256 = release_archive(&archive, "5.7.0")?;
+        let manifest = ReleaseManifest {
+            releases: vec![release("5.6.1"), newer],
+        };
+        let version_manager = VersionManager::new(dir.clone(), manifest);
+        install(&dir, "5.6.1")?;
+
+        let source = PivxdSource::Archive {
+            path: archive,
+            params: None,
+        };
+        version_manager.set_pivxd_source(source.clone()).await?;
+        let versions = version_manager.get_versions().await?;
+        assert_eq!(versions.current, "5.7.0");
+        assert_eq!(versions.previous.as_deref(), Some("5.6.1"));
+        assert_eq!(versions.installed, vec!["5.6.1", "5.7.0"]);
+        assert_eq!(versions.source, source);
+
+        // The archive is not one of the releases in the manifest
+        let unknown = temp_dir.path().join("pivx-5.8.0.tar.gz");
+        release_archive(&unknown, "5.8.0")?;
+        assert!(matches!(
+            version_manager
+                .set_pivxd_source(PivxdSource::Archive {
+                    path: unknown,
+                    params: None,
+                })
+                .await,
+            Err(PIVXErrors::WrongSha256Sum(None))
+        ));
+
+        let missing = temp_dir.path().join("pivxd");
+        assert!(matches!(
+            version_manager
+                .set_pivxd_source(PivxdSource::Binary {
+                    path: missing,
+                    params: None,
+                })
+                .await,
+            Err(PIVXErrors::PivxdNotFound)
+        ));
+        let source = PivxdSource::Binary {
+            path: binary_path(&dir, "5.6.1"),
+            params: None,
+        };
+        version_manager.set_pivxd_source(source.clone()).await?;
+        let versions = version_manager.get_versions().await?;
+        assert_eq!(versions.source, source);
+        assert_eq!(versions.current, "5.7.0");
+
+        // An archive already at the path it's decompressed from is the user's to keep
+        let own = dir.join("pivx-5.8.0.tar.gz");
+        let mut own_release = release("5.8.0");
+        own_release.platforms.get_mut(PLATFORM).unwrap().sha256 = release_archive(&own, "5.8.0")?;
+        let manifest = ReleaseManifest {
+            releases: vec![release("5.6.1"), own_release],
+        };
+        let own_manager = VersionManager::new(dir.clone(), manifest);
+        own_manager
+            .set_pivxd_source(PivxdSource::Archive {
+                path: own.clone(),
+                params: None,
+            })
+            .await?;
+        assert!(own.is_file());
+        assert!(binary_path(&dir, "5.8.0").is_file());
+        Ok(())
+    }
+}
+
+mod signature_verification {
+    use super::signature::{count_keys, find_sum, verify_signed_sums, RELEASE_KEYS};
+    use super::*;
+    use std::process::{Command, Stdio};
+    use tempdir::TempDir;
+
+    const SUMS: &str = "abcd  pivx-5.6.1-x86_64-linux-gnu.tar.gz\nef01 *pivx-5.6.1-win64.zip\n";
+
+    /**
+     * Creates a throwaway key in `home`, returning it armored with `SUMS` clearsigned by it
+     */
+    fn sign_sums(home: &Path) -> Result<(String, Vec<u8>), PIVXErrors> {
+        std::fs::create_dir_all(home)?;
+        let gpg = || {
+            let mut command = Command::new("gpg");
+            command.arg("--homedir").arg(home).args([
+                "--batch",
+                "--pinentry-mode",
+                "loopback",
+                "--passphrase",
+                "",
+            ]);
+            command
+        };
+        gpg()
+            .args(["--quick-gen-key", "Test <test@example.com>", "ed25519"])
+            .args(["sign", "never"])
+            .stderr(Stdio::null())
+            .status()?;
+        let keys = gpg().args(["--armor", "--export"]).output()?.stdout;
+        let mut signer = gpg()
+            .arg("--clearsign")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        signer.stdin.take().unwrap().write_all(SUMS.as_bytes())?;
+        let signed = signer.wait_with_output()?.stdout;
+        let _ = Command::new("gpgconf")
+            .arg("--homedir")
+            .arg(home)
+            .args(["--kill", "gpg-agent"])
+            .status();
+        Ok((String::from_utf8_lossy(&keys).into_owned(), signed))
+    }
+
+    #[tokio::test]
+    async fn verifies_signed_sums() -> Result<(), PIVXErrors> {
+        let temp_dir = TempDir::new("pivx-signature")?;
+        let (keys, signed) = sign_sums(&temp_dir.path().join("signer"))?;
+        let (other_keys, _) = sign_sums(&temp_dir.path().join("other"))?;
+        let work_dir = temp_dir.path().join("verify");
+
+        let sums = verify_signed_sums(&work_dir, &keys, &signed).await?;
+        assert_eq!(sums, SUMS);
+        assert!(!work_dir.exists());
+        assert_eq!(
+            find_sum(&sums, "pivx-5.6.1-x86_64-linux-gnu.tar.gz"),
+            Some("abcd")
+        );
+        assert_eq!(find_sum(&sums, "pivx-5.6.1-win64.zip"), Some("ef01"));
+        assert_eq!(find_sum(&sums, "pivx-5.6.1.tar.gz"), None);
+
+        let tampered = String::from_utf8_lossy(&signed).replace("abcd", "abce");
+        for (keys, signed) in [
+            (keys.as_str(), tampered.as_bytes()),
+            (other_keys.as_str(), signed.as_slice()),
+        ] {
+            assert!(matches!(
+                verify_signed_sums(&work_dir, keys, signed).await,
+                Err(PIVXErrors::InvalidSignature)
+            ));
+        }
+        assert!(matches!(
+            verify_signed_sums(&work_dir, "Not a key", &signed).await,
+            Err(PIVXErrors::NoReleaseKeys)
+        ));
+        // gpg can't write the keyring, which doesn't say anything about the signature
+        std::fs::create_dir_all(work_dir.join("keyring.gpg"))?;
+        assert!(matches!(
+            verify_signed_sums(&work_dir, &keys, &signed).await,
+            Err(PIVXErrors::GpgFailed)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn counts_keys() -> Result<(), PIVXErrors> {
+        let temp_dir = TempDir::new("pivx-signature")?;
+        let (keys, _) = sign_sums(&temp_dir.path().join("signer"))?;
+        let work_dir = temp_dir.path().join("count");
+        std::fs::create_dir(&work_dir)?;
+        assert_eq!(count_keys(&work_dir, &keys).await?, 1);
+        assert_eq!(count_keys(&work_dir, "Not a key").await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "release-keys.asc needs the maintainer keys from contrib/gitian-keys"]
+    async fn bundles_release_keys() -> Result<(), PIVXErrors> {
+        let temp_dir = TempDir::new("pivx-signature")?;
+        assert!(count_keys(temp_dir.path(), RELEASE_KEYS).await? > 0);
+        Ok(())
+    }
+}
+
+mod sapling_params {
+    use super::params::{copy_params_from, install_params_from};
+    use super::*;
+    use mockito::Matcher;
+    use tempdir::TempDir;
+
+    fn test_params() -> Vec<(String, String)> {
+        ["spend", "output"]
+            .iter()
+            .map(|name| (format!("{}.params", name), sha256::digest(*name)))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn fetches_missing_params() -> Result<(), PIVXErrors> {
+        let temp_dir = TempDir::new("sapling-params")?;
+        let mut server = mockito::Server::new_async().await;
+        let mut mocks = vec![];
+        for name in ["spend", "output"] {
+            let mock = server
+                .mock("GET", format!("/{}.params", name).as_str())
+                .with_body(name)
+                .expect(1)
+                .create_async()
+                .await;
+            mocks.push(mock);
+        }
+        let params = test_params();
+        let params: Vec<_> = params
+            .iter()
+            .map(|(n, s)| (n.as_str(), s.as_str()))
+            .collect();
+        install_params_from(temp_dir.path(), &server.url(), &params, |_| {}).await?;
+        assert_eq!(
+            std::fs::read_to_string(temp_dir.path().join("spend.params"))?,
+            "spend"
+        );
+        assert_eq!(
+            std::fs::read_to_string(temp_dir.path().join("output.params"))?,
+            "output"
+        );
+
+        // Valid params are not downloaded again
+        install_params_from(temp_dir.path(), &server.url(), &params, |_| {}).await?;
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replaces_corrupted_params() -> Result<(), PIVXErrors> {
+        let temp_dir = TempDir::new("sapling-params")?;
+        let path = temp_dir.path().join("spend.params");
+        std::fs::write(&path, "spand")?;
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/spend.params")
+            .match_header("range", "bytes=5-")
+            .with_status(416)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/spend.params")
+            .match_header("range", Matcher::Missing)
+            .with_body("spend")
+            .create_async()
+            .await;
+        let params = test_params();
+        let (name, sha256) = &params[0];
+        install_params_from(temp_dir.path(), &server.url(), &[(name, sha256)], |_| {}).await?;
+        assert_eq!(std::fs::read_to_string(path)?, "spend");
+        Ok(())
+    }
+
+    #[test]
+    fn copies_params_from_a_local_dir() -> Result<(), PIVXErrors> {
+        let temp_dir = TempDir::new("sapling-params")?;
+        let dir = temp_dir.path().join("params");
+        let source_dir = temp_dir.path().join("usb");
+        std::fs::create_dir(&source_dir)?;
+        let params = test_params();
+        let params: Vec<_> = params
+            .iter()
+            .map(|(n, s)| (n.as_str(), s.as_str()))
+            .collect();
+
+        // Offline, params that aren't installed can't be fetched
+        assert!(matches!(
+            copy_params_from(&dir, None, &params),
+            Err(PIVXErrors::SaplingParamsNotFound)
+        ));
+        std::fs::write(source_dir.join("spend.params"), "spend")?;
+        std::fs::write(source_dir.join("output.params"), "outptu")?;
+        assert!(matches!(
+            copy_params_from(&dir, Some(&source_dir), &params),
+            Err(PIVXErrors::SaplingParamsNotFound)
+        ));
+
+        std::fs::write(source_dir.join("output.params"), "output")?;
+        copy_params_from(&dir, Some(&source_dir), &params)?;
+        assert_eq!(std::fs::read_to_string(dir.join("spend.params"))?, "spend");
+        assert_eq!(
+            std::fs::read_to_string(dir.join("output.params"))?,
+            "output"
+        );
+        // Installed params don't need the local dir anymore
+        copy_params_from(&dir, None, &params)?;
+        Ok(())
+    }
+}
+
+mod archive_extraction {
+    use super::archive::extract_archive;
+    use super::*;
+    use tar::EntryType;
+    use tempdir::TempDir;
+
+    /**
+     * Writes a gzipped tar with `entries` of name, type and link name.
+     * Names are copied in the header as they are, so that they can't be sanitized
+     */
+    fn write_archive(path: &Path, entries: &[(&str, EntryType, &str)]) -> Result<(), PIVXErrors> {
+        let encoder = flate2::write::GzEncoder::new(File::create(path)?, Default::default());
+        let mut builder = tar::Builder::new(encoder);
+        for (name, entry_type, link_name) in entries {
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.as_old_mut().linkname[..link_name.len()].copy_from_slice(link_name.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_mode(0o755);
+            header.set_size(0);
+            header.set_cksum();
+            builder.append(&header, std::io::empty())?;
+        }
+        builder.into_inner()?.finish()?;
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unsafe_entries() -> Result<(), PIVXErrors> {
+        let temp_dir = TempDir::new("unsafe-archive")?;
+        let archive = temp_dir.path().join("pivxd.tar.gz");
+        let staging_dir = temp_dir.path().join("staging");
+        for entries in [
+            [("../evil", EntryType::Regular, "")],
+            [("/tmp/evil", EntryType::Regular, "")],
+            [("pivx/link", EntryType::Symlink, "../../evil")],
+            [("pivx/link", EntryType::Symlink, "/etc/passwd")],
+            [("pivx/link", EntryType::Link, "../evil")],
+            [("pivx/fifo", EntryType::Fifo, "")],
+        ] {
+            write_archive(&archive, &entries)?;
+            std::fs::create_dir_all(&staging_dir)?;
+            assert!(
+                matches!(
+                    extract_archive(&archive, &staging_dir),
+                    Err(PIVXErrors::UnsafeArchive)
+                ),
+                "{:?} was extracted",
+                entries[0].0
+            );
+        }
+        assert!(!temp_dir.path().join("evil").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn extracts_symlinks_inside_the_archive() -> Result<(), PIVXErrors> {
+        let temp_dir = TempDir::new("symlink-archive")?;
+        let archive = temp_dir.path().join("pivxd.tar.gz");
+        write_archive(
+            &archive,
+            &[
+                ("pivx/bin/pivxd", EntryType::Regular, ""),
+                ("pivx/lib/pivxd", EntryType::Symlink, "../bin/pivxd"),
+            ],
+        )?;
+        extract_archive(&archive, temp_dir.path())?;
+        assert_eq!(
+            std::fs::read_link(temp_dir.path().join("pivx/lib/pivxd"))?,
+            Path::new("../bin/pivxd")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn installs_only_archives_with_pivxd() -> Result<(), PIVXErrors> {
+        let temp_dir = TempDir::new("pivx-install")?;
+        let dir = temp_dir.path();
+        let definition = PIVXDefinition::new(PivxRelease {
+            version: "5.6.1".to_owned(),
+            url: String::new(),
+            sha256: String::new(),
+            sha256sums_url: String::new(),
+        });
+        let archive = dir.join(definition.get_archive_name());
+        assert_eq!(archive, dir.join("pivx-5.6.1.tar.gz"));
+        let entries = || -> Result<Vec<_>, PIVXErrors> {
+            let mut entries = std::fs::read_dir(dir)?
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect::<Result<Vec<_>, PIVXErrors>>()?;
+            entries.sort();
+            Ok(entries)
+        };
+
+        write_archive(&archive, &[("pivx-5.6.1/README", EntryType::Regular, "")])?;
+        assert!(matches!(
+            definition.decompress_archive(dir),
+            Err(PIVXErrors::PivxdNotFound)
+        ));
+        assert_eq!(entries()?, vec!["pivx-5.6.1.tar.gz"]);
+
+        write_archive(
+            &archive,
+            &[("pivx-5.6.1/bin/pivxd", EntryType::Regular, "")],
+        )?;
+        definition.decompress_archive(dir)?;
+        assert!(binary_path(dir, "5.6.1").is_file());
+        // The staging directory is removed, the archive is left to the caller
+        assert_eq!(entries()?, vec!["pivx-5.6.1", "pivx-5.6.1.tar.gz"]);
+        Ok(())
+    }
+}
+
+mod readiness_detection {
+    use super::*;
+    use crate::address_index::pivx_rpc::json_rpc::Error;
+    use crate::address_index::pivx_rpc::new_client;
+    use readiness::wait_for_rpc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const WARMUP: &str =
+        r#"{"result":null,"error":{"code":-28,"message":"Loading block index..."},"id":1}"#;
+    const READY: &str = r#"{"result":{"blocks":10},"error":null,"id":1}"#;
+
+    /**
+     * Returns a log with `line` sent to it so far.
+     * The sender has to be kept alive for the log not to end
+     */
+    async fn open_log(line: &str) -> (mpsc::Sender<String>, mpsc::Receiver<String>) {
+        let (sender, receiver) = mpsc::channel(10);
+        sender.send(line.to_owned()).await.unwrap();
+        (sender, receiver)
+    }
+
+    #[tokio::test]
+    async fn waits_for_rpc_warmup() -> Result<(), PIVXErrors> {
+        let mut server = mockito::Server::new_async().await;
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        server
+            .mock("POST", "/")
+            .with_body_from_request(move |_| {
+                match counter.fetch_add(1, Ordering::Relaxed) {
+                    0 | 1 => WARMUP,
+                    _ => READY,
+                }
+                .into()
+            })
+            .create_async()
+            .await;
+        let client = new_client(&server.url())?;
+        // Warming up takes longer than the timeout, but pivxd answers meanwhile
+        wait_for_rpc(&client, None, Duration::from_millis(100)).await?;
+        // Two warm-up answers, then getblockchaininfo and getnetworkinfo
+        assert_eq!(requests.load(Ordering::Relaxed), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_hung_warmup() -> Result<(), PIVXErrors> {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/")
+            .with_body(WARMUP)
+            .create_async()
+            .await;
+        let client = new_client(&server.url())?;
+        let start = std::time::Instant::now();
+        let result = wait_for_rpc(&client, None, Duration::from_millis(100)).await;
+        assert!(matches!(result, Err(PIVXErrors::PivxdLoadTimeout)));
+        // Warm-up answers extended the wait, but not past the cap
+        assert!(start.elapsed() >= Duration::from_millis(600));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fails_when_pivxd_stops() -> Result<(), PIVXErrors> {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/")
+            .with_body(WARMUP)
+            .create_async()
+            .await;
+        let client = new_client(&server.url())?;
+        let (_, log) = open_log("Loading block index...").await;
+        let result = wait_for_rpc(&client, Some(log), Duration::from_secs(10)).await;
+        assert!(matches!(result, Err(PIVXErrors::PivxdStopped)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn returns_other_rpc_errors() -> Result<(), PIVXErrors> {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/")
+            .with_body(r#"{"result":null,"error":{"code":-1,"message":"error"},"id":1}"#)
+            .create_async()
+            .await;
+        let client = new_client(&server.url())?;
+        let result = wait_for_rpc(&client, None, Duration::from_secs(10)).await;
+        assert!(matches!(
+            result,
+            Err(PIVXErrors::RpcError(Error::JSONRpc(err))) if err.code == -1
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_log_on_timeout() -> Result<(), PIVXErrors> {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/")
+            .with_status(401)
+            .create_async()
+            .await;
+        let client = new_client(&server.url())?;
+
+        let (_sender, log) = open_log("Loading block index...").await;
+        let result = wait_for_rpc(&client, Some(log), Duration::from_millis(100)).await;
+        assert!(matches!(result, Err(PIVXErrors::PivxdLoadTimeout)));
+
+        let (_sender, log) = open_log("asking peer for sporks").await;
+        wait_for_rpc(&client, Some(log), Duration::from_millis(100)).await?;
+        Ok(())
+    }
+}
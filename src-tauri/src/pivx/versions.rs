@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use global_function_macro::generate_global_functions;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, OnceCell};
+
+use super::{binary_path, readiness, PIVXDefinition};
+use crate::binary::{Binary, BinaryDefinition};
+use crate::error::PIVXErrors;
+
+/// Releases known to this build. Releases are never removed,
+/// so that the installed versions can always be started
+const RELEASES: &str = include_str!("releases.json");
+
+/// Name of this platform in the release manifest, as in the PIVX archive names
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub const PLATFORM: &str = "x86_64-linux-gnu";
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+pub const PLATFORM: &str = "aarch64-linux-gnu";
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+pub const PLATFORM: &str = "unsupported";
+
+/// Records the version that runs and the one to roll back to
+const STATE_FILE: &str = "pivxd-version.json";
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PlatformRelease {
+    pub url: String,
+    /// Hex encoded sha256 of the archive
+    pub sha256: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestRelease {
+    pub version: String,
+    /// Sums of the release archives, clearsigned by the maintainers
+    pub sha256sums_url: String,
+    /// Archives of the release by platform name
+    pub platforms: HashMap<String, PlatformRelease>,
+}
+
+/// Lists the pivxd releases with their archive for each platform
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReleaseManifest {
+    pub releases: Vec<ManifestRelease>,
+}
+
+impl ReleaseManifest {
+    pub fn bundled() -> crate::error::Result<Self> {
+        Ok(serde_json::from_str(RELEASES)?)
+    }
+
+    /**
+     * Returns the release of `version` for this platform
+     */
+    pub fn get_release(&self, version: &str) -> crate::error::Result<PivxRelease> {
+        self.releases
+            .iter()
+            .find(|release| release.version == version)
+            .and_then(|release| Some((release, release.platforms.get(PLATFORM)?)))
+            .map(|(release, platform)| PivxRelease {
+                version: version.to_owned(),
+                url: platform.url.clone(),
+                sha256: platform.sha256.clone(),
+                sha256sums_url: release.sha256sums_url.clone(),
+            })
+            .ok_or(PIVXErrors::UnknownRelease)
+    }
+
+    /**
+     * Returns the version whose archive for this platform has `sha256`
+     */
+    pub fn find_version(&self, sha256: &str) -> Option<String> {
+        self.releases
+            .iter()
+            .find(|release| {
+                matches!(release.platforms.get(PLATFORM), Some(platform) if platform.sha256 == sha256)
+            })
+            .map(|release| release.version.clone())
+    }
+
+    /**
+     * Returns the versions released for this platform, oldest first
+     */
+    pub fn get_available_versions(&self) -> Vec<String> {
+        let mut versions: Vec<_> = self
+            .releases
+            .iter()
+            .filter(|release| release.platforms.contains_key(PLATFORM))
+            .map(|release| release.version.clone())
+            .collect();
+        sort_versions(&mut versions);
+        versions
+    }
+}
+
+/// A pivxd release for this platform
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PivxRelease {
+    pub version: String,
+    pub url: String,
+    pub sha256: String,
+    pub sha256sums_url: String,
+}
+
+/// Where pivxd comes from. Air-gapped machines use a local archive or binary,
+/// and the sapling params are copied from `params` when they're not installed yet
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PivxdSource {
+    /// Releases are downloaded from the url in the manifest, and the params from the Zcash mirror
+    #[default]
+    Download,
+    /// An archive of a release in the manifest, checked against its sha256.
+    /// Its signature is not verified, as that needs the network
+    Archive {
+        path: PathBuf,
+        #[serde(default)]
+        params: Option<PathBuf>,
+    },
+    /// An existing pivxd, which is started as is
+    Binary {
+        path: PathBuf,
+        #[serde(default)]
+        params: Option<PathBuf>,
+    },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+struct VersionState {
+    current: Option<String>,
+    previous: Option<String>,
+    #[serde(default)]
+    source: PivxdSource,
+    /// Seconds pivxd may not answer RPC calls while starting, `readiness::LOAD_TIMEOUT` if unset
+    #[serde(default)]
+    load_timeout: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PivxVersions {
+    /// Version pivxd is started with, switching takes effect on the next start
+    pub current: String,
+    /// Version to roll back to
+    pub previous: Option<String>,
+    pub installed: Vec<String>,
+    pub available: Vec<String>,
+    pub source: PivxdSource,
+    /// Seconds pivxd may not answer RPC calls while starting
+    pub load_timeout: u64,
+}
+
+/**
+ * Sorts dotted versions numerically, so that 5.10.0 comes after 5.9.0
+ */
+fn sort_versions(versions: &mut [String]) {
+    versions.sort_by_key(|version| {
+        version
+            .split('.')
+            .map(|part| part.parse::<u64>().unwrap_or(0))
+            .collect::<Vec<_>>()
+    });
+}
+
+/// Installs pivxd versions next to each other in the data dir and selects the one to start
+pub struct VersionManager {
+    dir: PathBuf,
+    manifest: ReleaseManifest,
+    /// Held while installing or switching, so that the state has a single writer
+    lock: Mutex<()>,
+}
+
+static VERSION_MANAGER: OnceCell<VersionManager> = OnceCell::const_new();
+
+pub async fn get_version_manager() -> &'static VersionManager {
+    VERSION_MANAGER
+        .get_or_init(|| async {
+            VersionManager::new(
+                Binary::get_data_dir().expect("No data dir"),
+                ReleaseManifest::bundled().expect("Invalid release manifest"),
+            )
+        })
+        .await
+}
+
+impl VersionManager {
+    pub fn new(dir: PathBuf, manifest: ReleaseManifest) -> Self {
+        Self {
+            dir,
+            manifest,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn read_state(&self) -> crate::error::Result<VersionState> {
+        match std::fs::read(self.dir.join(STATE_FILE)) {
+            Ok(state) => Ok(serde_json::from_slice(&state)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(VersionState::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /**
+     * Writes the state to a temporary file and renames it over the old one,
+     * so that an interrupted switch leaves either state but never a partial one
+     */
+    fn write_state(&self, state: &VersionState) -> crate::error::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let tmp_path = self.dir.join(format!("{}.tmp", STATE_FILE));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(state)?)?;
+        file.sync_all()?;
+        std::fs::rename(tmp_path, self.dir.join(STATE_FILE))?;
+        Ok(())
+    }
+
+    /**
+     * Returns the versions with a pivxd binary in the data dir, oldest first
+     */
+    fn get_installed_versions(&self) -> crate::error::Result<Vec<String>> {
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut versions = vec![];
+        for entry in std::fs::read_dir(&self.dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if let Some(version) = name.strip_prefix("pivx-") {
+                if binary_path(&self.dir, version).exists() {
+                    versions.push(version.to_owned());
+                }
+            }
+        }
+        sort_versions(&mut versions);
+        Ok(versions)
+    }
+
+    /**
+     * Without a state the newest installed version runs, as installs from before
+     * versions were managed only have one, otherwise the newest available
+     */
+    fn get_current_version(&self, state: &VersionState) -> crate::error::Result<String> {
+        if let Some(current) = &state.current {
+            return Ok(current.clone());
+        }
+        self.get_installed_versions()?
+            .pop()
+            .or_else(|| self.manifest.get_available_versions().pop())
+            .ok_or(PIVXErrors::UnknownRelease)
+    }
+
+    /**
+     * Records the current version if there is no state yet,
+     * so that installing another version doesn't change it
+     */
+    fn pin_current_version(&self) -> crate::error::Result<String> {
+        let state = self.read_state()?;
+        let current = self.get_current_version(&state)?;
+        if state.current.is_none() {
+            self.write_state(&VersionState {
+                current: Some(current.clone()),
+                ..state
+            })?;
+        }
+        Ok(current)
+    }
+
+    /**
+     * Returns the definition of the version pivxd should be started with
+     */
+    pub fn get_current_definition(&self) -> crate::error::Result<PIVXDefinition> {
+        let version = self.pin_current_version()?;
+        let definition = PIVXDefinition::new(self.manifest.get_release(&version)?);
+        Ok(match self.read_state()?.load_timeout {
+            Some(load_timeout) => definition.with_load_timeout(Duration::from_secs(load_timeout)),
+            None => definition,
+        })
+    }
+
+    async fn install_locked(&self, version: &str) -> crate::error::Result<()> {
+        self.pin_current_version()?;
+        let definition = PIVXDefinition::new(self.manifest.get_release(version)?);
+        Binary::install(&self.dir, &definition).await
+    }
+
+    fn switch_locked(&self, version: &str) -> crate::error::Result<()> {
+        if !self.get_installed_versions()?.iter().any(|v| v == version) {
+            return Err(PIVXErrors::VersionNotInstalled);
+        }
+        let state = self.read_state()?;
+        let current = self.get_current_version(&state)?;
+        if current == version {
+            return Ok(());
+        }
+        self.write_state(&VersionState {
+            current: Some(version.to_owned()),
+            previous: Some(current),
+            ..state
+        })
+    }
+
+    /**
+     * Installs the release in the archive at `path`, returning its version
+     */
+    fn install_archive_locked(&self, path: &Path) -> crate::error::Result<String> {
+        self.pin_current_version()?;
+        let digest = sha256::try_digest(path).map_err(|e| PIVXErrors::WrongSha256Sum(Some(e)))?;
+        let version = self
+            .manifest
+            .find_version(&digest)
+            .ok_or(PIVXErrors::WrongSha256Sum(None))?;
+        let definition = PIVXDefinition::new(self.manifest.get_release(&version)?);
+        Binary::install_from_archive(&self.dir, path, &definition)?;
+        Ok(version)
+    }
+
+    /**
+     * Starts the current pivxd from the configured source,
+     * installing it and the sapling params first if needed.
+     * Offline sources never download, params missing from their dir are reported instead
+     */
+    pub async fn start_pivxd(&self) -> crate::error::Result<Binary> {
+        let _lock = self.lock.lock().await;
+        let definition = self.get_current_definition()?;
+        match self.read_state()?.source {
+            PivxdSource::Download => {
+                super::params::install_params().await?;
+                Binary::new_by_fetching(&definition).await
+            }
+            PivxdSource::Archive { path, params } => {
+                super::params::install_local_params(params.as_deref())?;
+                Binary::install_from_archive(&self.dir, &path, &definition)?;
+                let binary_path = definition.get_binary_path(&self.dir);
+                Binary::new_by_path(&binary_path.to_string_lossy(), &definition)
+            }
+            PivxdSource::Binary { path, params } => {
+                super::params::install_local_params(params.as_deref())?;
+                Binary::new_by_path(&path.to_string_lossy(), &definition)
+            }
+        }
+    }
+}
+
+#[generate_global_functions]
+impl VersionManager {
+    pub async fn get_versions(&self) -> crate::error::Result<PivxVersions> {
+        let state = self.read_state()?;
+        Ok(PivxVersions {
+            current: self.get_current_version(&state)?,
+            previous: state.previous,
+            installed: self.get_installed_versions()?,
+            available: self.manifest.get_available_versions(),
+            source: state.source,
+            load_timeout: state
+                .load_timeout
+                .unwrap_or(readiness::LOAD_TIMEOUT.as_secs()),
+        })
+    }
+
+    /// Sets where pivxd comes from on the next start. An archive and params are installed,
+    /// and the archive switched to, right away, so that wrong ones are reported here
+    pub async fn set_pivxd_source(&self, source: PivxdSource) -> crate::error::Result<()> {
+        let _lock = self.lock.lock().await;
+        if let PivxdSource::Archive {
+            params: Some(params),
+            ..
+        }
+        | PivxdSource::Binary {
+            params: Some(params),
+            ..
+        } = &source
+        {
+            super::params::install_local_params(Some(params))?;
+        }
+        match &source {
+            PivxdSource::Download => {}
+            PivxdSource::Archive { path, .. } => {
+                let version = self.install_archive_locked(path)?;
+                self.switch_locked(&version)?;
+            }
+            PivxdSource::Binary { path, .. } if !path.is_file() => {
+                return Err(PIVXErrors::PivxdNotFound);
+            }
+            PivxdSource::Binary { .. } => {}
+        }
+        let state = self.read_state()?;
+        self.write_state(&VersionState { source, ..state })
+    }
+
+    /// Sets how many seconds pivxd may not answer RPC calls while starting,
+    /// None restores the default. It takes effect on the next start
+    pub async fn set_load_timeout(&self, seconds: Option<u64>) -> crate::error::Result<()> {
+        let _lock = self.lock.lock().await;
+        let state = self.read_state()?;
+        self.write_state(&VersionState {
+            load_timeout: seconds,
+            ..state
+        })
+    }
+
+    /// Downloads `version` next to the installed ones, without switching to it
+    pub async fn install_version(&self, version: &str) -> crate::error::Result<()> {
+        let _lock = self.lock.lock().await;
+        self.install_locked(version).await
+    }
+
+    /// Starts pivxd with the installed `version` from the next start,
+    /// the current version is kept to roll back to
+    pub async fn switch_version(&self, version: &str) -> crate::error::Result<()> {
+        let _lock = self.lock.lock().await;
+        self.switch_locked(version)
+    }
+
+    /// Installs the newest available version and switches to it, returning the version
+    pub async fn upgrade(&self) -> crate::error::Result<String> {
+        let _lock = self.lock.lock().await;
+        let version = self
+            .manifest
+            .get_available_versions()
+            .pop()
+            .ok_or(PIVXErrors::UnknownRelease)?;
+        self.install_locked(&version).await?;
+        self.switch_locked(&version)?;
+        Ok(version)
+    }
+
+    /// Switches back to the previous version, returning it
+    pub async fn rollback(&self) -> crate::error::Result<String> {
+        let _lock = self.lock.lock().await;
+        let previous = self
+            .read_state()?
+            .previous
+            .ok_or(PIVXErrors::NoPreviousVersion)?;
+        self.switch_locked(&previous)?;
+        Ok(previous)
+    }
+}